@@ -0,0 +1,106 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+
+pub const FILE_NAME: &str = "assets.toml";
+
+static DEFAULT: &str = include_str!("../assets.toml");
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AssetsFile {
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    from: String,
+    to: String,
+}
+
+/// Rewrite rules of the asset URLs, loaded from `assets.toml`.
+#[derive(Debug)]
+pub struct AssetUrls {
+    rules: Vec<Rule>,
+    unmatched: Mutex<BTreeSet<String>>,
+}
+
+impl AssetUrls {
+    fn parse(content: &str) -> Result<Self> {
+        let file: AssetsFile = toml::from_str(content)?;
+        for rule in &file.rules {
+            for prefix in [&rule.from, &rule.to] {
+                if !prefix.ends_with('/') {
+                    return Err(anyhow!("{} does not end with /", prefix));
+                }
+            }
+            Url::parse(&rule.from).map_err(|err| anyhow!("{}: {}", rule.from, err))?;
+            let to = Url::parse(&rule.to).map_err(|err| anyhow!("{}: {}", rule.to, err))?;
+            if !["http", "https", "file"].contains(&to.scheme()) {
+                return Err(anyhow!("{}: unsupported scheme {}", rule.to, to.scheme()));
+            }
+        }
+        Ok(AssetUrls {
+            rules: file.rules,
+            unmatched: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    fn matching<'s, 'u>(&'s self, url: &'u str) -> Option<(&'s Rule, &'u str)> {
+        let found = self.rules.iter()
+            .find_map(|rule| url.strip_prefix(rule.from.as_str()).map(|rest| (rule, rest)));
+        if found.is_none() {
+            self.unmatched.lock().unwrap().insert(url.to_string());
+        }
+        found
+    }
+
+    /// Rewrites an asset URL of the Rebrickable data, or returns `None` if it is empty or matches
+    /// no rule.
+    pub fn rewrite(&self, url: &str) -> Option<String> {
+        if url.is_empty() {
+            return None;
+        }
+        self.matching(url).map(|(rule, rest)| format!("{}{}", rule.to, rest))
+    }
+
+    /// Path of the rewritten URL of `url`, relative to the `to` prefix of its rule.
+    pub fn relative_path<'a>(&self, url: &'a str) -> Option<&'a str> {
+        if url.is_empty() {
+            return None;
+        }
+        self.matching(url).map(|(_, rest)| rest)
+    }
+
+    /// The URLs of the Rebrickable data that are rewritten to a URL with this `path`, by order of
+    /// the rules.
+    pub fn source_urls(&self, path: &str) -> Vec<String> {
+        self.rules.iter()
+            .filter_map(|rule| {
+                let to = Url::parse(&rule.to).ok()?;
+                path.strip_prefix(to.path()).map(|rest| format!("{}{}", rule.from, rest))
+            })
+            .collect()
+    }
+
+    /// The non-empty URLs given so far that matched no rule.
+    pub fn unmatched(&self) -> Vec<String> {
+        self.unmatched.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Loads `assets.toml` from the working directory, or the built-in copy if there is none.
+pub fn load(workdir: &Path) -> Result<AssetUrls> {
+    let path = workdir.join(FILE_NAME);
+    if path.exists() {
+        let content = fs::read_to_string(&path)?;
+        AssetUrls::parse(&content).map_err(|err| anyhow!("{}: {}", path.display(), err))
+    } else {
+        AssetUrls::parse(DEFAULT)
+    }
+}
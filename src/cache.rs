@@ -1,29 +1,563 @@
-
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use anyhow::{anyhow, Result};
+use reqwest::{StatusCode, Url};
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderName, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE, RETRY_AFTER};
+use crate::manifest::{self, Manifest};
+use crate::utils::format_size;
+
+// Longest wait between two attempts, before the jitter
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// How often the manifest is saved during a run, so an interrupted one keeps most of its entries
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct Options {
+    /// Number of concurrent downloads
+    pub jobs: usize,
+    /// Maximum number of requests per second to a same host
+    pub rate: Option<f64>,
+    /// Number of retries after a transient failure
+    pub retries: u32,
+}
+
+pub enum Outcome {
+    Cached,
+    Downloaded,
+    Unchanged,
+    Updated,
+}
+
+/// Spaces out the requests made to each host.
+struct HostLimiter {
+    interval: Option<Duration>,
+    next: Mutex<HashMap<String, Instant>>,
+}
+
+impl HostLimiter {
+    fn new(rate: Option<f64>) -> Self {
+        HostLimiter {
+            interval: rate.filter(|r| *r > 0.0).map(|r| Duration::from_secs_f64(1.0 / r)),
+            next: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn wait(&self, url: &str) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        let host = Url::parse(url).ok()
+            .and_then(|u| u.host_str().map(String::from))
+            .unwrap_or_default();
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let slot = next.get(&host).map_or(now, |n| (*n).max(now));
+            next.insert(host, slot + interval);
+            slot
+        };
+        thread::sleep(slot.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// A failed download attempt, worth retrying or not.
+enum Failure {
+    Transient(anyhow::Error, Option<Duration>),
+    Permanent(anyhow::Error),
+}
+
+impl From<reqwest::Error> for Failure {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_builder() || err.is_redirect() {
+            Failure::Permanent(err.into())
+        } else {
+            Failure::Transient(err.into(), None)
+        }
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Transient(err.into(), None)
+    }
+}
+
+// Downloads are written next to their final path and renamed once complete, so an interrupted
+// download is never mistaken for a cached file, and can be resumed.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+// The validator of the response a partial download started with, so it is only resumed if the
+// remote file is still the same
+fn validator_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".part.validator");
+    path.with_file_name(name)
+}
+
+fn remove_partial(path: &Path) -> io::Result<()> {
+    for partial in [partial_path(path), validator_path(path)] {
+        if partial.exists() {
+            fs::remove_file(&partial)?;
+        }
+    }
+    Ok(())
+}
+
+// Start of the range of a `Content-Range: bytes <start>-<end>/<size>` header
+fn content_range_start(resp: &reqwest::blocking::Response) -> Option<u64> {
+    let range = header(resp, CONTENT_RANGE)?;
+    let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+fn retry_after(resp: &reqwest::blocking::Response) -> Option<Duration> {
+    resp.headers().get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Path of the cached copy of `url`: the URL without its scheme, under `cache_dir`.
+pub fn cache_path(url: &str, cache_dir: &Path) -> Result<PathBuf> {
+    match url.split_once("//") {
+        Some((_, path)) => Ok(cache_dir.join(path)),
+        None => Err(anyhow!("invalid URL: {}", url)),
+    }
+}
+
+// All the files under `dir`, except those under `skip`
+fn list_files(dir: &Path, skip: Option<&Path>, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if skip.is_some_and(|skip| path == skip) {
+            continue;
+        }
+        if path.is_dir() {
+            list_files(&path, skip, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Moves a file, copying it when it can't be renamed, e.g. to another file system
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to.parent().unwrap())?;
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+/// A problem found on a cached file by `Mirror::verify`.
+pub enum Problem {
+    Missing,
+    Empty,
+    ChecksumMismatch,
+    NotAnImage,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Problem::Missing => "missing",
+            Problem::Empty => "zero-byte file",
+            Problem::ChecksumMismatch => "checksum mismatch",
+            Problem::NotAnImage => "not an image",
+        })
+    }
+}
 
-pub fn mirror(url: &str, cache_dir: &str) -> Result<PathBuf> {
-    if let Some((_, path)) = url.split_once("//") {
+// Headers of a download worth keeping in the manifest
+struct Downloaded {
+    content_type: Option<String>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Downloaded {
+    fn has_validator(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+}
+
+enum Fetched {
+    /// Written to the cache
+    Modified(Downloaded),
+    /// Unchanged since the validators of the request, with the headers of the response
+    NotModified(Downloaded),
+}
+
+fn header(resp: &reqwest::blocking::Response, name: HeaderName) -> Option<String> {
+    resp.headers().get(name).and_then(|v| v.to_str().ok()).map(String::from)
+}
+
+pub struct Mirror {
+    cache_dir: PathBuf,
+    client: Client,
+    limiter: HostLimiter,
+    jobs: usize,
+    retries: u32,
+    manifest: Mutex<Manifest>,
+    saved_at: Mutex<Instant>,
+}
+
+impl Mirror {
+    pub fn new(cache_dir: &Path, options: &Options) -> Result<Self> {
+        // a single client for all the workers, so connections are reused
+        let client = Client::builder()
+            .user_agent(concat!("briq-utils/", env!("CARGO_PKG_VERSION")))
+            .pool_max_idle_per_host(options.jobs)
+            .build()?;
+        Ok(Mirror {
+            cache_dir: cache_dir.to_owned(),
+            client,
+            limiter: HostLimiter::new(options.rate),
+            jobs: options.jobs.max(1),
+            retries: options.retries,
+            manifest: Mutex::new(Manifest::load(cache_dir)?),
+            saved_at: Mutex::new(Instant::now()),
+        })
+    }
+
+    pub fn save_manifest(&self) -> Result<()> {
+        self.manifest.lock().unwrap().save(&self.cache_dir)?;
+        *self.saved_at.lock().unwrap() = Instant::now();
+        Ok(())
+    }
+
+    // Saves the changed `manifest` if it wasn't for a while
+    fn save_periodically(&self, manifest: &Manifest) -> Result<()> {
+        let mut saved_at = self.saved_at.lock().unwrap();
+        if saved_at.elapsed() >= SAVE_INTERVAL {
+            manifest.save(&self.cache_dir)?;
+            *saved_at = Instant::now();
+        }
+        Ok(())
+    }
+
+    pub fn mirror(&self, url: &str) -> Result<(PathBuf, Outcome)> {
         // return early if path already exists, make sure the parent directories exists
-        let path = Path::new(cache_dir).join(path);
+        let path = cache_path(url, &self.cache_dir)?;
         if path.exists() {
-            println!("{} already exists.", path.display());
-            return Ok(path);
+            return Ok((path, Outcome::Cached));
         }
         fs::create_dir_all(path.parent().unwrap())?;
-       
-        // simple blocking download of url into path
-        let mut resp = reqwest::blocking::get(url)?;
-        if !resp.status().is_success() {
-            return Err(anyhow!("Request failed: {}", resp.status()));
-        }
-        let mut out = fs::File::create(&path)?;
-        io::copy(&mut resp, &mut out)?;
-        println!("{} downloaded.", url);        
-        Ok(path)
-    } else {
-        Err(anyhow!("invalid URL: {}", url))
+
+        let Fetched::Modified(downloaded) = self.with_retries(url, || self.download(url, &path, None))? else {
+            return Err(anyhow!("unexpected 304 Not Modified"));
+        };
+        self.record(url, &path, downloaded)?;
+        Ok((path, Outcome::Downloaded))
+    }
+
+    /// Like `mirror`, but also revalidates cached files with the ETag and Last-Modified of their
+    /// manifest entry, or the time of the file when it has none, and downloads them again if they
+    /// changed.
+    pub fn refresh(&self, url: &str) -> Result<(PathBuf, Outcome)> {
+        let path = cache_path(url, &self.cache_dir)?;
+        if !path.exists() {
+            return self.mirror(url);
+        }
+        let entry = self.manifest.lock().unwrap().entries.get(url).cloned();
+        let content_type = entry.as_ref().and_then(|e| e.content_type.clone());
+        let validators = entry
+            .map(|e| Downloaded { content_type: e.content_type, etag: e.etag, last_modified: e.last_modified })
+            .filter(|v| v.has_validator());
+        let known = validators.is_some();
+        // cached before the manifest, or added to it by verify: the file is at least as recent as
+        // the remote one was when downloaded
+        let validators = match validators {
+            Some(validators) => validators,
+            None => Downloaded {
+                content_type: None,
+                etag: None,
+                last_modified: Some(httpdate::fmt_http_date(fs::metadata(&path)?.modified()?)),
+            },
+        };
+        // a leftover partial download can't be trusted to be of the current version
+        remove_partial(&path)?;
+        match self.with_retries(url, || self.download(url, &path, Some(&validators)))? {
+            Fetched::NotModified(headers) => {
+                // so the next refreshes use the validators of the server
+                if !known && headers.has_validator() {
+                    self.record(url, &path, Downloaded { content_type: headers.content_type.or(content_type), ..headers })?;
+                }
+                Ok((path, Outcome::Unchanged))
+            }
+            Fetched::Modified(downloaded) => {
+                self.record(url, &path, downloaded)?;
+                Ok((path, Outcome::Updated))
+            }
+        }
+    }
+
+    fn with_retries<T>(&self, url: &str, f: impl Fn() -> Result<T, Failure>) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Ok(value) => return Ok(value),
+                Err(Failure::Permanent(err)) => return Err(err),
+                Err(Failure::Transient(err, _)) if attempt >= self.retries => return Err(err),
+                Err(Failure::Transient(err, delay)) => {
+                    // exponential backoff with some jitter, unless the server said how long to wait
+                    let delay = delay.unwrap_or_else(|| {
+                        let backoff = 2u64.checked_pow(attempt).and_then(|factor| factor.checked_mul(500))
+                            .map_or(MAX_BACKOFF, Duration::from_millis)
+                            .min(MAX_BACKOFF);
+                        backoff + Duration::from_millis(rand::random_range(0..250))
+                    });
+                    eprintln!("{} {}, retrying in {:.1}s.", url, err, delay.as_secs_f64());
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    fn record(&self, url: &str, path: &Path, downloaded: Downloaded) -> Result<()> {
+        let (size, sha256) = manifest::hash_file(path)?;
+        let entry = manifest::Entry {
+            path: path.strip_prefix(&self.cache_dir)?.to_owned(),
+            size,
+            sha256,
+            content_type: downloaded.content_type,
+            etag: downloaded.etag,
+            last_modified: downloaded.last_modified,
+            fetched_at: manifest::now(),
+        };
+        let mut manifest = self.manifest.lock().unwrap();
+        manifest.entries.insert(url.to_string(), entry);
+        self.save_periodically(&manifest)
+    }
+
+    // Downloads `url` into `path`. With a manifest `entry`, the request is conditional and
+    // returns None when the remote file didn't change.
+    fn download(&self, url: &str, path: &Path, validators: Option<&Downloaded>) -> Result<Fetched, Failure> {
+        let partial = partial_path(path);
+        let validator_path = validator_path(path);
+        // without a validator, there's no telling whether the rest of the file is of the same version
+        let validator = fs::read_to_string(&validator_path).ok();
+        let offset = match validator {
+            Some(_) => fs::metadata(&partial).map_or(0, |m| m.len()),
+            None => 0,
+        };
+        self.limiter.wait(url);
+        let mut req = self.client.get(url);
+        if let (true, Some(validator)) = (offset > 0, &validator) {
+            // the whole file is sent instead if it changed since
+            req = req.header(RANGE, format!("bytes={}-", offset)).header(IF_RANGE, validator);
+        }
+        if let Some(etag) = validators.and_then(|v| v.etag.as_ref()) {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = validators.and_then(|v| v.last_modified.as_ref()) {
+            req = req.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        let mut resp = req.send()?;
+        let status = resp.status();
+        let headers = Downloaded {
+            content_type: header(&resp, CONTENT_TYPE),
+            etag: header(&resp, ETAG),
+            last_modified: header(&resp, LAST_MODIFIED),
+        };
+        let mut out = match status {
+            StatusCode::NOT_MODIFIED => return Ok(Fetched::NotModified(headers)),
+            StatusCode::PARTIAL_CONTENT if offset > 0 && content_range_start(&resp) == Some(offset) => {
+                fs::OpenOptions::new().append(true).open(&partial)?
+            }
+            StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => {
+                // the partial file doesn't match the remote one anymore, start over
+                remove_partial(path)?;
+                return Err(Failure::Transient(anyhow!("Request failed: {}", status), Some(Duration::ZERO)));
+            }
+            s if s.is_success() => {
+                // weak ETags can't be used in If-Range
+                let validator = header(&resp, ETAG).filter(|etag| !etag.starts_with("W/"))
+                    .or_else(|| header(&resp, LAST_MODIFIED));
+                match validator {
+                    Some(validator) => fs::write(&validator_path, validator)?,
+                    None => remove_partial(path)?,
+                }
+                fs::File::create(&partial)?
+            }
+            s if s == StatusCode::TOO_MANY_REQUESTS || s.is_server_error() => {
+                return Err(Failure::Transient(anyhow!("Request failed: {}", s), retry_after(&resp)));
+            }
+            s => return Err(Failure::Permanent(anyhow!("Request failed: {}", s))),
+        };
+        let expected = resp.content_length();
+        let copied = io::copy(&mut resp, &mut out)?;
+        if expected.is_some_and(|expected| copied != expected) {
+            return Err(Failure::Transient(anyhow!("incomplete download: {} of {:?} bytes", copied, expected), None));
+        }
+        out.sync_all()?;
+        fs::rename(&partial, path)?;
+        if validator_path.exists() {
+            fs::remove_file(&validator_path)?;
+        }
+        Ok(Fetched::Modified(headers))
+    }
+
+    /// Checks the cached copy of every URL: it must exist, be an image, and match its checksum
+    /// in the manifest. Bad files are removed, so they are downloaded again by the next mirror.
+    /// Files missing from the manifest are added to it.
+    pub fn verify(&self, urls: &[String]) -> Vec<(String, Problem)> {
+        let problems = Mutex::new(vec![]);
+        self.for_each(urls, |url, progress| {
+            match self.verify_one(url) {
+                Ok(None) => {}
+                Ok(Some(problem)) => {
+                    println!("{:.2}% {} {}.", progress, url, problem);
+                    problems.lock().unwrap().push((url.to_string(), problem));
+                }
+                Err(err) => eprintln!("\x1b[31m{:.2}% {} {}\x1b[0m", progress, url, err),
+            }
+        });
+        problems.into_inner().unwrap()
+    }
+
+    fn verify_one(&self, url: &str) -> Result<Option<Problem>> {
+        let path = cache_path(url, &self.cache_dir)?;
+        if !path.exists() {
+            self.manifest.lock().unwrap().entries.remove(url);
+            return Ok(Some(Problem::Missing));
+        }
+        let (size, sha256) = manifest::hash_file(&path)?;
+        let expected = self.manifest.lock().unwrap().entries.get(url).map(|e| e.sha256.clone());
+        let problem = if size == 0 {
+            Some(Problem::Empty)
+        } else if expected.as_ref().is_some_and(|expected| *expected != sha256) {
+            Some(Problem::ChecksumMismatch)
+        } else if !manifest::is_image(&path)? {
+            Some(Problem::NotAnImage)
+        } else {
+            None
+        };
+        let mut manifest = self.manifest.lock().unwrap();
+        if problem.is_some() {
+            fs::remove_file(&path)?;
+            manifest.entries.remove(url);
+        } else if expected.is_none() {
+            let fetched_at = fs::metadata(&path)?.modified()?
+                .duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+            manifest.entries.insert(url.to_string(), manifest::Entry {
+                path: path.strip_prefix(&self.cache_dir)?.to_owned(),
+                size,
+                sha256,
+                content_type: None,
+                etag: None,
+                last_modified: None,
+                fetched_at,
+            });
+        }
+        self.save_periodically(&manifest)?;
+        Ok(problem)
+    }
+
+    // Calls `f` on every URL, with the progress, from `jobs` workers
+    pub fn for_each<F>(&self, urls: &[String], f: F)
+    where
+        F: Fn(&str, f64) + Sync,
+    {
+        let total = urls.len();
+        let next = AtomicUsize::new(0);
+        let done = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..self.jobs {
+                scope.spawn(|| {
+                    while let Some(url) = urls.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let progress = (done.fetch_add(1, Ordering::Relaxed) + 1) as f64 / total as f64 * 100.0;
+                        f(url, progress);
+                    }
+                });
+            }
+        });
+    }
+
+    /// Removes the cached files that are not of one of `urls`, or moves them to `quarantine`.
+    /// With `dry_run`, only reports them. Returns the number of files and their total size.
+    pub fn prune(&self, urls: &[String], quarantine: Option<&Path>, dry_run: bool) -> Result<(usize, u64)> {
+        let mut keep: HashSet<PathBuf> = HashSet::new();
+        for url in urls {
+            let path = cache_path(url, &self.cache_dir)?;
+            keep.insert(partial_path(&path));
+            keep.insert(validator_path(&path));
+            keep.insert(path);
+        }
+        keep.insert(self.cache_dir.join(manifest::FILE_NAME));
+        let mut files = vec![];
+        if self.cache_dir.exists() {
+            list_files(&self.cache_dir, quarantine, &mut files)?;
+        }
+        files.sort();
+        let mut count = 0;
+        let mut total = 0;
+        for path in files.iter().filter(|p| !keep.contains(*p)) {
+            let size = fs::metadata(path)?.len();
+            count += 1;
+            total += size;
+            if dry_run {
+                println!("{} ({}) would be removed.", path.display(), format_size(size));
+                continue;
+            }
+            match quarantine {
+                Some(quarantine) => {
+                    move_file(path, &quarantine.join(path.strip_prefix(&self.cache_dir)?))?;
+                    println!("{} ({}) moved to quarantine.", path.display(), format_size(size));
+                }
+                None => {
+                    fs::remove_file(path)?;
+                    println!("{} ({}) removed.", path.display(), format_size(size));
+                }
+            }
+            // clean up the directories left empty
+            let mut dir = path.parent();
+            while let Some(d) = dir.filter(|d| *d != self.cache_dir) {
+                if fs::remove_dir(d).is_err() {
+                    break;
+                }
+                dir = d.parent();
+            }
+        }
+        if !dry_run {
+            let urls: HashSet<&String> = urls.iter().collect();
+            self.manifest.lock().unwrap().entries.retain(|url, _| urls.contains(url));
+        }
+        Ok((count, total))
+    }
+
+    /// Mirrors all `urls` with `jobs` workers, revalidating the cached ones with `refresh`.
+    /// Returns the number of failures.
+    pub fn mirror_all(&self, urls: &[String], refresh: bool) -> usize {
+        let failed = AtomicUsize::new(0);
+        self.for_each(urls, |url, progress| {
+            let result = if refresh { self.refresh(url) } else { self.mirror(url) };
+            match result {
+                Ok((path, Outcome::Cached)) => println!("{:.2}% {} already exists.", progress, path.display()),
+                Ok((_, Outcome::Downloaded)) => println!("{:.2}% {} downloaded.", progress, url),
+                Ok((_, Outcome::Unchanged)) => println!("{:.2}% {} unchanged.", progress, url),
+                Ok((_, Outcome::Updated)) => println!("{:.2}% {} updated.", progress, url),
+                Err(err) => {
+                    failed.fetch_add(1, Ordering::Relaxed);
+                    eprintln!("\x1b[31m{:.2}% {} {}\x1b[0m", progress, url, err);
+                }
+            }
+        });
+        failed.into_inner()
     }
 }
@@ -1,41 +1,227 @@
+use std::fmt;
 use std::fs::File;
-use std::path::PathBuf;
-use std::collections::{HashMap};
-use anyhow::{Result};
-use csv::Reader;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use anyhow::{anyhow, Result};
+use csv::{Reader, StringRecord};
+use flate2::read::MultiGzDecoder;
+use serde::de::DeserializeOwned;
+use crate::findings::{self, Finding, Kind, Severity};
+use crate::ids::{self, IdMappings};
 
 macro_rules! make_csv_reader {
     ($fn_name:ident, $type:ty) => {
-        fn $fn_name(path: PathBuf) -> anyhow::Result<Vec<$type>> {
-            let mut records = Vec::<$type>::new();
-            let mut rdr = Reader::from_reader(File::open(&path)?);
-            for result in rdr.deserialize() {
-                let record: $type = result?;
-                records.push(record);
-            }
-            Ok(records)
+        fn $fn_name(path: PathBuf, errors: &mut Option<Vec<ParseError>>) -> anyhow::Result<Vec<$type>> {
+            read_records::<$type>(&path, errors)
         }
     };
 }
 
 macro_rules! read_csv {
-    ($workdir:expr, $file:literal, $func:ident) => {
-        $func($workdir.join($file))?
+    ($workdir:expr, $file:literal, $func:ident, $errors:expr) => {
+        $func($workdir.join($file), $errors)?
+    };
+    // files older working directories may not have, read as empty
+    ($workdir:expr, $file:literal, $func:ident, $errors:expr, optional) => {
+        if csv_candidates(&$workdir.join($file)).iter().any(|c| c.is_file()) {
+            $func($workdir.join($file), $errors)?
+        } else {
+            eprintln!("warning: {}: file not found (also tried .gz), read as empty", $workdir.join($file).display());
+            vec![]
+        }
     };
 }
 
+// Rebrickable publishes its downloads gzipped and with underscores in the file names
+// (inventory_parts.csv.gz), so look for those variants before giving up on `path`.
+fn csv_candidates(path: &Path) -> Vec<PathBuf> {
+    let mut candidates = vec![];
+    let mut names = vec![];
+    if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+        names.push(name.to_owned());
+        if name.contains('-') {
+            names.push(name.replace('-', "_"));
+        }
+    }
+    for name in names {
+        candidates.push(path.with_file_name(format!("{name}.gz")));
+        candidates.push(path.with_file_name(name));
+    }
+    candidates
+}
+
+fn open_csv(path: &Path) -> Result<(PathBuf, Box<dyn Read>)> {
+    for candidate in csv_candidates(path) {
+        if !candidate.is_file() {
+            continue;
+        }
+        let file = File::open(&candidate)?;
+        if candidate.extension().is_some_and(|ext| ext == "gz") {
+            return Ok((candidate, Box::new(MultiGzDecoder::new(file))));
+        }
+        return Ok((candidate, Box::new(file)));
+    }
+    Err(anyhow!("{}: file not found (also tried .gz)", path.display()))
+}
+
+/// A row that could not be read or deserialized.
+#[derive(Debug)]
+pub struct ParseError {
+    pub file: PathBuf,
+    pub line: u64,
+    pub column: Option<String>,
+    pub value: Option<String>,
+    pub message: String,
+}
+
+impl ParseError {
+    fn new(file: &Path, headers: &StringRecord, row: Option<&StringRecord>, err: &csv::Error) -> Self {
+        let line = err.position()
+            .or(row.and_then(|r| r.position()))
+            .map_or(0, |p| p.line());
+        let (field, message) = match err.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => (err.field(), err.kind().to_string()),
+            csv::ErrorKind::UnequalLengths { expected_len, len, .. } =>
+                (None, format!("found {} fields, expected {}", len, expected_len)),
+            csv::ErrorKind::Utf8 { err, .. } => (None, format!("invalid UTF-8 in field {}", err.field() + 1)),
+            _ => (None, err.to_string()),
+        };
+        let field = field.and_then(|f| usize::try_from(f).ok());
+        ParseError {
+            file: file.to_owned(),
+            line,
+            column: field.and_then(|f| headers.get(f)).map(String::from),
+            value: field.and_then(|f| row.and_then(|r| r.get(f))).map(String::from),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: ", self.file.display(), self.line)?;
+        if let Some(column) = &self.column {
+            write!(f, "column '{}'", column)?;
+            if let Some(value) = &self.value {
+                write!(f, " = {:?}", value)?;
+            }
+            write!(f, ": ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl ParseError {
+    /// The skipped row as an error finding, keyed by line.
+    pub fn to_finding(&self) -> Finding {
+        let mut rows = vec![];
+        if let Some(column) = &self.column {
+            rows.push(format!("column '{}'", column));
+        }
+        if let Some(value) = &self.value {
+            rows.push(format!("value {:?}", value));
+        }
+        rows.push(self.message.clone());
+        Finding {
+            kind: Kind::BadRow,
+            severity: Severity::Error,
+            file: self.file.file_name().map_or_else(|| self.file.display().to_string(), |n| n.to_string_lossy().to_string()),
+            key: format!("line {}", self.line),
+            rows,
+        }
+    }
+}
+
+// Bad rows abort the read, unless `errors` is set, in which case they are skipped and collected.
+fn read_records<T: DeserializeOwned>(path: &Path, errors: &mut Option<Vec<ParseError>>) -> Result<Vec<T>> {
+    let (path, reader) = open_csv(path)?;
+    let mut rdr = Reader::from_reader(reader);
+    let headers = rdr.headers()?.clone();
+    let mut records = Vec::<T>::new();
+    for result in rdr.records() {
+        let err = match result {
+            Ok(row) => match row.deserialize(Some(&headers)) {
+                Ok(record) => {
+                    records.push(record);
+                    continue;
+                }
+                Err(err) => ParseError::new(&path, &headers, Some(&row), &err),
+            },
+            Err(err) if err.is_io_error() => return Err(err.into()),
+            Err(err) => ParseError::new(&path, &headers, None, &err),
+        };
+        match errors {
+            Some(errors) => errors.push(err),
+            None => return Err(err.into()),
+        }
+    }
+    Ok(records)
+}
+
+/// A color of `colors.csv`, written as 6 hexadecimal digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Rgb {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+impl TryFrom<String> for Rgb {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid RGB color '{}', expected 6 hexadecimal digits", value);
+        if value.len() != 6 {
+            return Err(invalid());
+        }
+        let component = |i: usize| value.get(i..i + 2)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(invalid);
+        Ok(Rgb { red: component(0)?, green: component(2)?, blue: component(4)? })
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02X}{:02X}{:02X}", self.red, self.green, self.blue)
+    }
+}
+
+// Rebrickable writes booleans as True/False, and as t/f in older downloads
+fn deserialize_bool<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    let value = <String as serde::Deserialize>::deserialize(deserializer)?;
+    match value.as_str() {
+        "True" | "true" | "t" => Ok(true),
+        "False" | "false" | "f" => Ok(false),
+        _ => Err(serde::de::Error::custom(format!("invalid boolean '{}', expected True or False", value))),
+    }
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub struct ColorRecord {
     pub id: i32,
     pub name: String,
-    pub rgb: String,
-    pub is_trans: String,
+    pub rgb: Rgb,
+    #[serde(deserialize_with = "deserialize_bool")]
+    pub is_trans: bool,
     pub num_parts: u32,
     pub num_sets: u32,
     pub y1: Option<u16>,
     pub y2: Option<u16>,
 }
 
+#[derive(Debug, serde::Deserialize)]
+pub struct ElementRecord {
+    pub element_id: String,
+    pub part_num: String,
+    pub color_id: i32,
+    pub design_id: Option<String>,
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub struct InventoryRecord {
     pub id: u32,
@@ -82,6 +268,30 @@ pub struct PartCategoryRecord {
     pub name: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all(serialize = "snake_case"))]
+pub enum PartRelationshipType {
+    #[serde(rename(deserialize = "A"))]
+    Alternate,
+    #[serde(rename(deserialize = "B"))]
+    SubPart,
+    #[serde(rename(deserialize = "M"))]
+    Mold,
+    #[serde(rename(deserialize = "P"))]
+    Print,
+    #[serde(rename(deserialize = "R"))]
+    Pair,
+    #[serde(rename(deserialize = "T"))]
+    Pattern,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PartRelationshipRecord {
+    pub rel_type: PartRelationshipType,
+    pub child_part_num: String,
+    pub parent_part_num: String,
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub struct SetRecord {
     pub set_num: String,
@@ -102,32 +312,204 @@ pub struct ThemeRecord {
 #[derive(Debug)]
 pub struct Data {
     pub colors: Vec<ColorRecord>,
+    pub elements: Vec<ElementRecord>,
     pub inventories: Vec<InventoryRecord>,
     pub inventories_minifigs: Vec<InventoryMinifigRecord>,
     pub inventories_parts: Vec<InventoryPartRecord>,
     pub minifigs: Vec<MinifigRecord>,
     pub parts: Vec<PartRecord>,
     pub part_categories: Vec<PartCategoryRecord>,
+    pub part_relationships: Vec<PartRelationshipRecord>,
     pub sets: Vec<SetRecord>,
     pub themes: Vec<ThemeRecord>,
+    pub parse_errors: Vec<ParseError>,
+    pub findings: Vec<Finding>,
+    pub id_mappings: IdMappings,
 }
 
 make_csv_reader!(read_colors, ColorRecord);
+make_csv_reader!(read_elements, ElementRecord);
 make_csv_reader!(read_inventories, InventoryRecord);
 make_csv_reader!(read_inventories_minifigs, InventoryMinifigRecord);
 make_csv_reader!(read_inventories_parts, InventoryPartRecord);
 make_csv_reader!(read_minifigs, MinifigRecord);
 make_csv_reader!(read_part_categories, PartCategoryRecord);
+make_csv_reader!(read_part_relationships, PartRelationshipRecord);
 make_csv_reader!(read_parts, PartRecord);
 make_csv_reader!(read_sets, SetRecord);
 make_csv_reader!(read_themes, ThemeRecord);
 
-fn normalize_theme_ids(data: &mut Data) {
-    let mut tmp_map: HashMap<u32, u32> = HashMap::new();
-    for (i, theme) in data.themes.iter().enumerate() {
-        tmp_map.insert(i.try_into().unwrap(), theme.id);
+/// What to do with a row referencing a color or theme that doesn't exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Policy {
+    /// Drop the row
+    Skip,
+    /// Reference the `[Unknown]` color or theme instead, added if needed
+    Placeholder,
+    /// Fail with an error
+    Abort,
+}
+
+/// A row referencing a color or theme that doesn't exist.
+#[derive(Debug)]
+pub struct MissingReference {
+    pub file: &'static str,
+    pub row: String,
+    /// `color` or `theme`
+    pub target: &'static str,
+    pub id: String,
+}
+
+impl fmt::Display for MissingReference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {} {} does not exist", self.file, self.row, self.target, self.id)
+    }
+}
+
+impl std::error::Error for MissingReference {}
+
+// Rebrickable's own ID for the colors it doesn't know
+const UNKNOWN_COLOR_ID: i32 = -1;
+// Rebrickable's theme IDs start at 1
+const UNKNOWN_THEME_ID: u32 = 0;
+
+// Applies the policy to a missing reference: `None` to drop the row, or the ID to reference instead
+fn resolve<T>(policy: Policy, reference: MissingReference, placeholder: T) -> Result<Option<T>> {
+    match policy {
+        Policy::Skip => {
+            eprintln!("{}: skipped", reference);
+            Ok(None)
+        }
+        Policy::Placeholder => {
+            eprintln!("{}: replaced by [Unknown]", reference);
+            Ok(Some(placeholder))
+        }
+        Policy::Abort => Err(reference.into()),
+    }
+}
+
+// Every theme and set ends up referencing existing themes, so all their IDs are mapped
+fn resolve_theme_references(data: &mut Data, policy: Policy) -> Result<()> {
+    let mut known: HashSet<u32> = data.themes.iter().map(|t| t.id).collect();
+    let mut placeholder = false;
+    // skipping a theme leaves its sub-themes without parent in turn
+    let mut skipped = true;
+    while skipped {
+        skipped = false;
+        let mut themes = Vec::with_capacity(data.themes.len());
+        for mut theme in std::mem::take(&mut data.themes) {
+            if let Some(parent_id) = theme.parent_id.filter(|id| !known.contains(id)) {
+                let reference = MissingReference {
+                    file: "themes.csv",
+                    row: format!("theme {}", theme.id),
+                    target: "theme",
+                    id: parent_id.to_string(),
+                };
+                match resolve(policy, reference, UNKNOWN_THEME_ID)? {
+                    Some(id) => {
+                        theme.parent_id = Some(id);
+                        placeholder = true;
+                    }
+                    None => {
+                        known.remove(&theme.id);
+                        skipped = true;
+                        continue;
+                    }
+                }
+            }
+            themes.push(theme);
+        }
+        data.themes = themes;
+    }
+    let mut sets = Vec::with_capacity(data.sets.len());
+    for mut set in std::mem::take(&mut data.sets) {
+        if !known.contains(&set.theme_id) {
+            let reference = MissingReference {
+                file: "sets.csv",
+                row: format!("set {}", set.set_num),
+                target: "theme",
+                id: set.theme_id.to_string(),
+            };
+            match resolve(policy, reference, UNKNOWN_THEME_ID)? {
+                Some(id) => {
+                    set.theme_id = id;
+                    placeholder = true;
+                }
+                None => continue,
+            }
+        }
+        sets.push(set);
+    }
+    data.sets = sets;
+    if placeholder && !known.contains(&UNKNOWN_THEME_ID) {
+        data.themes.push(ThemeRecord { id: UNKNOWN_THEME_ID, name: "[Unknown]".to_string(), parent_id: None });
     }
-    let ids_map: HashMap<u32, u32> = tmp_map.into_iter().map(|(k, v)| (v, k)).collect();
+    Ok(())
+}
+
+// Every inventory part and element ends up referencing existing colors, so all their IDs are mapped
+fn resolve_color_references(data: &mut Data, policy: Policy) -> Result<()> {
+    let known: HashSet<i32> = data.colors.iter().map(|c| c.id).collect();
+    let mut placeholder = false;
+    let mut parts = Vec::with_capacity(data.inventories_parts.len());
+    for mut part in std::mem::take(&mut data.inventories_parts) {
+        if !known.contains(&part.color_id) {
+            let reference = MissingReference {
+                file: "inventory-parts.csv",
+                row: format!("inventory {} part {}", part.inventory_id, part.part_num),
+                target: "color",
+                id: part.color_id.to_string(),
+            };
+            match resolve(policy, reference, UNKNOWN_COLOR_ID)? {
+                Some(id) => {
+                    part.color_id = id;
+                    placeholder = true;
+                }
+                None => continue,
+            }
+        }
+        parts.push(part);
+    }
+    data.inventories_parts = parts;
+    let mut elements = Vec::with_capacity(data.elements.len());
+    for mut element in std::mem::take(&mut data.elements) {
+        if !known.contains(&element.color_id) {
+            let reference = MissingReference {
+                file: "elements.csv",
+                row: format!("element {}", element.element_id),
+                target: "color",
+                id: element.color_id.to_string(),
+            };
+            match resolve(policy, reference, UNKNOWN_COLOR_ID)? {
+                Some(id) => {
+                    element.color_id = id;
+                    placeholder = true;
+                }
+                None => continue,
+            }
+        }
+        elements.push(element);
+    }
+    data.elements = elements;
+    if placeholder && !known.contains(&UNKNOWN_COLOR_ID) {
+        data.colors.push(ColorRecord {
+            id: UNKNOWN_COLOR_ID,
+            name: "[Unknown]".to_string(),
+            rgb: Rgb { red: 0x00, green: 0x33, blue: 0xB2 },
+            is_trans: false,
+            num_parts: 0,
+            num_sets: 0,
+            y1: None,
+            y2: None,
+        });
+    }
+    Ok(())
+}
+
+// The references are resolved first, so every ID is mapped
+fn normalize_theme_ids(data: &mut Data) {
+    data.id_mappings.assign_themes(data.themes.iter().map(|t| t.id));
+    let ids_map = &data.id_mappings.themes;
     for theme in &mut data.themes {
         theme.id = ids_map[&theme.id];
         if let Some(parent_id) = theme.parent_id {
@@ -137,36 +519,56 @@ fn normalize_theme_ids(data: &mut Data) {
     for set in &mut data.sets {
         set.theme_id = ids_map[&set.theme_id];
     } 
+    data.themes.sort_by_key(|t| t.id);
 }
 
+// The references are resolved first, so every ID is mapped
 fn normalize_color_ids(data: &mut Data) {
-    let mut tmp_map: HashMap<i32, i32> = HashMap::new();
-    for (i, col) in data.colors.iter().enumerate() {
-        tmp_map.insert(i.try_into().unwrap(), col.id);
-    }
-    //println!("{:?}", tmp_map);
-    let ids_map: HashMap<i32, i32> = tmp_map.into_iter().map(|(k, v)| (v, k)).collect();
+    data.id_mappings.assign_colors(data.colors.iter().map(|c| c.id));
+    let ids_map = &data.id_mappings.colors;
     for color in &mut data.colors {
         color.id = ids_map[&color.id];
      }
     for part in &mut data.inventories_parts {
         part.color_id = ids_map[&part.color_id];
     }
+    for element in &mut data.elements {
+        element.color_id = ids_map[&element.color_id];
+    }
+    data.colors.sort_by_key(|c| c.id);
 }
 
-pub fn read_all(workdir: &str) -> Result<Box<Data>> {
+pub fn read_all(workdir: &str, skip_bad_rows: bool, policy: Policy) -> Result<Box<Data>> {
+    let id_mappings = ids::load(Path::new(workdir))?;
+    read_all_with_ids(workdir, skip_bad_rows, policy, id_mappings)
+}
+
+/// Reads the working directory like `read_all`, with the given mappings of the IDs instead of its
+/// `ids.json`.
+pub fn read_all_with_ids(workdir: &str, skip_bad_rows: bool, policy: Policy, id_mappings: IdMappings) -> Result<Box<Data>> {
     let workdir: PathBuf = workdir.into();
+    let mut errors = if skip_bad_rows { Some(vec![]) } else { None };
+    let errors = &mut errors;
     let mut data = Data{
-        colors: read_csv!(workdir, "colors.csv", read_colors),
-        inventories: read_csv!(workdir, "inventories.csv", read_inventories),
-        inventories_minifigs: read_csv!(workdir, "inventory-minifigs.csv", read_inventories_minifigs),
-        inventories_parts: read_csv!(workdir, "inventory-parts.csv", read_inventories_parts),
-        minifigs: read_csv!(workdir, "minifigs.csv", read_minifigs),
-        parts: read_csv!(workdir, "parts.csv", read_parts),
-        part_categories: read_csv!(workdir, "part-categories.csv", read_part_categories),
-        sets: read_csv!(workdir, "sets.csv", read_sets),
-        themes: read_csv!(workdir, "themes.csv", read_themes),
+        colors: read_csv!(workdir, "colors.csv", read_colors, errors),
+        elements: read_csv!(workdir, "elements.csv", read_elements, errors, optional),
+        inventories: read_csv!(workdir, "inventories.csv", read_inventories, errors),
+        inventories_minifigs: read_csv!(workdir, "inventory-minifigs.csv", read_inventories_minifigs, errors),
+        inventories_parts: read_csv!(workdir, "inventory-parts.csv", read_inventories_parts, errors),
+        minifigs: read_csv!(workdir, "minifigs.csv", read_minifigs, errors),
+        parts: read_csv!(workdir, "parts.csv", read_parts, errors),
+        part_categories: read_csv!(workdir, "part-categories.csv", read_part_categories, errors),
+        part_relationships: read_csv!(workdir, "part-relationships.csv", read_part_relationships, errors, optional),
+        sets: read_csv!(workdir, "sets.csv", read_sets, errors),
+        themes: read_csv!(workdir, "themes.csv", read_themes, errors),
+        parse_errors: vec![],
+        findings: vec![],
+        id_mappings,
     };
+    data.parse_errors = errors.take().unwrap_or_default();
+    data.findings = validate(&data);
+    resolve_color_references(&mut data, policy)?;
+    resolve_theme_references(&mut data, policy)?;
     normalize_color_ids(&mut data);
     normalize_theme_ids(&mut data);
 
@@ -175,25 +577,128 @@ pub fn read_all(workdir: &str) -> Result<Box<Data>> {
 
 }
 
-pub fn validate(data: &Data) {
-    let mut parts_map: HashMap<String, bool> = HashMap::new();
-    for part in &data.parts {
-        parts_map.insert(part.part_num.clone(), true);
-    }
-    for part in &data.inventories_parts {
-        if !parts_map.contains_key(&part.part_num) {
-            eprintln!("{}: part does not exist", part.part_num)
-        }
-    }
-    let mut minifigs_map: HashMap<String, bool> = HashMap::new();
-    for minifig in &data.minifigs {
-        minifigs_map.insert(minifig.fig_num.clone(), true);
+// A finding per key referenced by `records` that doesn't exist
+fn missing<T>(kind: Kind, file: &str, records: &[T], reference: impl Fn(&T) -> Option<(String, String)>) -> Vec<Finding> {
+    findings::group(kind, Severity::Error, file, records.iter().filter_map(reference))
+}
+
+// A finding per key shared by several of `records`
+fn duplicates<T>(file: &str, records: &[T], key: impl Fn(&T) -> String, row: impl Fn(&T) -> String) -> Vec<Finding> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for record in records {
+        *counts.entry(key(record)).or_default() += 1;
     }
-    for minifig in &data.inventories_minifigs {
-        if !minifigs_map.contains_key(&minifig.fig_num) {
-            eprintln!("{}: minifig does not exist", minifig.fig_num);
+    findings::group(Kind::DuplicateKey, Severity::Error, file, records.iter()
+        .filter(|r| counts[&key(r)] > 1)
+        .map(|r| (key(r), row(r))))
+}
+
+// A finding per loop in the parents of the themes, keyed by the path from its smallest ID
+fn theme_cycles(themes: &[ThemeRecord]) -> Vec<Finding> {
+    let parents: HashMap<u32, Option<u32>> = themes.iter().map(|t| (t.id, t.parent_id)).collect();
+    let mut visited: HashSet<u32> = HashSet::new();
+    let mut cycles: BTreeSet<Vec<u32>> = BTreeSet::new();
+    for theme in themes {
+        let mut path: Vec<u32> = vec![];
+        let mut id = Some(theme.id);
+        while let Some(current) = id.filter(|id| !visited.contains(id)) {
+            if let Some(start) = path.iter().position(|id| *id == current) {
+                let mut cycle = path[start..].to_vec();
+                let min = cycle.iter().enumerate().min_by_key(|(_, id)| **id).map_or(0, |(i, _)| i);
+                cycle.rotate_left(min);
+                cycles.insert(cycle);
+                break;
+            }
+            path.push(current);
+            id = parents.get(&current).copied().flatten();
         }
+        visited.extend(path);
     }
+    cycles.into_iter()
+        .map(|cycle| Finding {
+            kind: Kind::ThemeCycle,
+            severity: Severity::Error,
+            file: "themes.csv".to_string(),
+            key: cycle.iter().chain(cycle.first()).map(|id| id.to_string()).collect::<Vec<_>>().join(" -> "),
+            rows: cycle.iter().map(|id| format!("theme {}", id)).collect(),
+        })
+        .collect()
+}
+
+/// Checks the referential integrity of the data: unique primary keys, and references to existing
+/// rows. Also checks that the colors were produced from `y1` to `y2`. Called on the data as read,
+/// before the missing references are resolved and the IDs normalized, so the findings have all
+/// the rows at fault with their Rebrickable IDs.
+pub fn validate(data: &Data) -> Vec<Finding> {
+    let mut findings = vec![];
+    findings.extend(duplicates("colors.csv", &data.colors, |c| c.id.to_string(), |c| c.name.clone()));
+    findings.extend(duplicates("elements.csv", &data.elements, |e| e.element_id.clone(), |e| format!("part {}", e.part_num)));
+    findings.extend(duplicates("inventories.csv", &data.inventories, |i| i.id.to_string(), |i| format!("{} version {}", i.set_num, i.version)));
+    findings.extend(duplicates("minifigs.csv", &data.minifigs, |m| m.fig_num.clone(), |m| m.name.clone()));
+    findings.extend(duplicates("parts.csv", &data.parts, |p| p.part_num.clone(), |p| p.name.clone()));
+    findings.extend(duplicates("part-categories.csv", &data.part_categories, |c| c.id.to_string(), |c| c.name.clone()));
+    findings.extend(duplicates("sets.csv", &data.sets, |s| s.set_num.clone(), |s| s.name.clone()));
+    findings.extend(duplicates("themes.csv", &data.themes, |t| t.id.to_string(), |t| t.name.clone()));
+
+    let colors: HashSet<i32> = data.colors.iter().map(|c| c.id).collect();
+    let inventories: HashSet<u32> = data.inventories.iter().map(|i| i.id).collect();
+    let minifigs: HashSet<&str> = data.minifigs.iter().map(|m| m.fig_num.as_str()).collect();
+    let parts: HashSet<&str> = data.parts.iter().map(|p| p.part_num.as_str()).collect();
+    let part_categories: HashSet<u32> = data.part_categories.iter().map(|c| c.id).collect();
+    let sets: HashSet<&str> = data.sets.iter().map(|s| s.set_num.as_str()).collect();
+    let themes: HashSet<u32> = data.themes.iter().map(|t| t.id).collect();
+    findings.extend(missing(Kind::MissingSetOrMinifig, "inventories.csv", &data.inventories, |i| {
+        let exists = sets.contains(i.set_num.as_str()) || minifigs.contains(i.set_num.as_str());
+        (!exists).then(|| (i.set_num.clone(), format!("inventory {}", i.id)))
+    }));
+    findings.extend(missing(Kind::MissingPart, "inventory-parts.csv", &data.inventories_parts, |p| {
+        (!parts.contains(p.part_num.as_str())).then(|| (p.part_num.clone(), format!("inventory {}", p.inventory_id)))
+    }));
+    findings.extend(missing(Kind::MissingColor, "inventory-parts.csv", &data.inventories_parts, |p| {
+        (!colors.contains(&p.color_id)).then(|| (p.color_id.to_string(), format!("inventory {} part {}", p.inventory_id, p.part_num)))
+    }));
+    findings.extend(missing(Kind::MissingInventory, "inventory-parts.csv", &data.inventories_parts, |p| {
+        (!inventories.contains(&p.inventory_id)).then(|| (p.inventory_id.to_string(), format!("part {}", p.part_num)))
+    }));
+    findings.extend(missing(Kind::MissingMinifig, "inventory-minifigs.csv", &data.inventories_minifigs, |m| {
+        (!minifigs.contains(m.fig_num.as_str())).then(|| (m.fig_num.clone(), format!("inventory {}", m.inventory_id)))
+    }));
+    findings.extend(missing(Kind::MissingInventory, "inventory-minifigs.csv", &data.inventories_minifigs, |m| {
+        (!inventories.contains(&m.inventory_id)).then(|| (m.inventory_id.to_string(), format!("minifig {}", m.fig_num)))
+    }));
+    findings.extend(missing(Kind::MissingPart, "elements.csv", &data.elements, |e| {
+        (!parts.contains(e.part_num.as_str())).then(|| (e.part_num.clone(), format!("element {}", e.element_id)))
+    }));
+    findings.extend(missing(Kind::MissingColor, "elements.csv", &data.elements, |e| {
+        (!colors.contains(&e.color_id)).then(|| (e.color_id.to_string(), format!("element {}", e.element_id)))
+    }));
+    // either part of a relationship can be missing, or both
+    findings.extend(findings::group(Kind::MissingPart, Severity::Error, "part-relationships.csv",
+        data.part_relationships.iter().flat_map(|r| {
+            [&r.child_part_num, &r.parent_part_num].into_iter()
+                .filter(|part| !parts.contains(part.as_str()))
+                .map(move |part| (part.clone(), format!("{:?} {} -> {}", r.rel_type, r.child_part_num, r.parent_part_num)))
+        })));
+    findings.extend(missing(Kind::MissingPartCategory, "parts.csv", &data.parts, |p| {
+        (!part_categories.contains(&p.part_cat_id)).then(|| (p.part_cat_id.to_string(), format!("part {}", p.part_num)))
+    }));
+    findings.extend(missing(Kind::MissingTheme, "sets.csv", &data.sets, |s| {
+        (!themes.contains(&s.theme_id)).then(|| (s.theme_id.to_string(), format!("set {}", s.set_num)))
+    }));
+    findings.extend(missing(Kind::MissingTheme, "themes.csv", &data.themes, |t| {
+        t.parent_id.filter(|id| !themes.contains(id)).map(|id| (id.to_string(), format!("theme {}", t.id)))
+    }));
+    findings.extend(theme_cycles(&data.themes));
+    findings.extend(data.colors.iter()
+        .filter(|c| matches!((c.y1, c.y2), (Some(y1), Some(y2)) if y1 > y2))
+        .map(|c| Finding {
+            kind: Kind::InvalidYears,
+            severity: Severity::Error,
+            file: "colors.csv".to_string(),
+            key: c.id.to_string(),
+            rows: vec![c.name.clone()],
+        }));
+    findings
 }
 
 
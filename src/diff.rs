@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+use serde_json::{json, Map, Value};
+
+// Collections of init.json and the fields identifying their entries
+const COLLECTIONS: [(&str, &[&str]); 8] = [
+    ("colors", &["id"]),
+    ("elements", &["id"]),
+    ("minifigs", &["number"]),
+    ("parts", &["number"]),
+    ("part_categories", &["id"]),
+    ("part_relationships", &["kind", "child_number", "parent_number"]),
+    ("sets", &["number"]),
+    ("themes", &["id"]),
+];
+
+#[derive(Debug)]
+pub struct Summary {
+    pub collection: &'static str,
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+}
+
+/// Builds the delta between two init.json documents: for every collection, the entries `added`
+/// and `changed` in full, and the keys of the entries `removed`.
+pub fn diff(old: &Value, new: &Value) -> (Value, Vec<Summary>) {
+    let mut delta = Map::new();
+    let mut summaries = vec![];
+    for (collection, key_fields) in COLLECTIONS {
+        let old = index(old, collection, key_fields);
+        let new = index(new, collection, key_fields);
+        let added: Vec<&Value> = new.iter()
+            .filter(|(k, _)| !old.contains_key(*k))
+            .map(|(_, v)| *v)
+            .collect();
+        let changed: Vec<&Value> = new.iter()
+            .filter(|(k, v)| old.get(*k).is_some_and(|old| old != *v))
+            .map(|(_, v)| *v)
+            .collect();
+        let removed: Vec<Value> = old.iter()
+            .filter(|(k, _)| !new.contains_key(*k))
+            .map(|(_, v)| key(v, key_fields))
+            .collect();
+        summaries.push(Summary {
+            collection,
+            added: added.len(),
+            removed: removed.len(),
+            changed: changed.len(),
+        });
+        delta.insert(collection.to_string(), json!({
+            "added": added,
+            "removed": removed,
+            "changed": changed,
+        }));
+    }
+    (Value::Object(delta), summaries)
+}
+
+fn index<'a>(data: &'a Value, collection: &str, key_fields: &[&str]) -> BTreeMap<String, &'a Value> {
+    let mut entries = BTreeMap::new();
+    if let Some(values) = data.get(collection).and_then(Value::as_array) {
+        for value in values {
+            entries.insert(key(value, key_fields).to_string(), value);
+        }
+    }
+    entries
+}
+
+fn key(value: &Value, key_fields: &[&str]) -> Value {
+    if let [field] = key_fields {
+        return value[field].clone();
+    }
+    let fields: Map<String, Value> = key_fields.iter()
+        .map(|f| (f.to_string(), value[f].clone()))
+        .collect();
+    Value::Object(fields)
+}
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::utils::pluralize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    BadRow,
+    DuplicateKey,
+    InvalidYears,
+    MissingColor,
+    MissingInventory,
+    MissingMinifig,
+    MissingPart,
+    MissingPartCategory,
+    MissingSetOrMinifig,
+    MissingTheme,
+    ThemeCycle,
+    UnknownSet,
+}
+
+impl Kind {
+    fn message(&self) -> &'static str {
+        match self {
+            Kind::BadRow => "row skipped, it could not be read",
+            Kind::DuplicateKey => "key is not unique",
+            Kind::InvalidYears => "y1 is after y2",
+            Kind::MissingColor => "color does not exist",
+            Kind::MissingInventory => "inventory does not exist",
+            Kind::MissingMinifig => "minifig does not exist",
+            Kind::MissingPart => "part does not exist",
+            Kind::MissingPartCategory => "part category does not exist",
+            Kind::MissingSetOrMinifig => "set or minifig does not exist",
+            Kind::MissingTheme => "theme does not exist",
+            Kind::ThemeCycle => "themes are their own ancestors",
+            Kind::UnknownSet => "set does not exist",
+        }
+    }
+}
+
+/// A problem found in the data: `key` is what is wrong in `file`, e.g. the number of a part that
+/// doesn't exist, and `rows` the rows referencing it.
+#[derive(Debug, serde::Serialize)]
+pub struct Finding {
+    pub kind: Kind,
+    pub severity: Severity,
+    pub file: String,
+    pub key: String,
+    pub rows: Vec<String>,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}: {}: {}", severity, self.file, self.key, self.kind.message())?;
+        // the first rows are enough to track the problem down
+        const MAX_ROWS: usize = 5;
+        if !self.rows.is_empty() {
+            write!(f, " ({}", self.rows[..self.rows.len().min(MAX_ROWS)].join(", "))?;
+            if self.rows.len() > MAX_ROWS {
+                write!(f, " and {} more", self.rows.len() - MAX_ROWS)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+/// Groups the `(key, row)` references by key, in a finding per key.
+pub fn group<I>(kind: Kind, severity: Severity, file: &str, references: I) -> Vec<Finding>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut rows: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (key, row) in references {
+        rows.entry(key).or_default().push(row);
+    }
+    rows.into_iter()
+        .map(|(key, rows)| Finding { kind, severity, file: file.to_string(), key, rows })
+        .collect()
+}
+
+pub fn count(findings: &[Finding], severity: Severity) -> usize {
+    findings.iter().filter(|f| f.severity == severity).count()
+}
+
+pub fn to_text(findings: &[Finding]) -> String {
+    let mut text = String::new();
+    for finding in findings {
+        text.push_str(&format!("{}\n", finding));
+    }
+    text.push_str(&format!("{}, {}.\n",
+        pluralize(count(findings, Severity::Error), "error"),
+        pluralize(count(findings, Severity::Warning), "warning")));
+    text
+}
+
+pub fn to_json(findings: &[Finding]) -> serde_json::Result<String> {
+    serde_json::to_string_pretty(&serde_json::json!({
+        "errors": count(findings, Severity::Error),
+        "warnings": count(findings, Severity::Warning),
+        "findings": findings,
+    }))
+}
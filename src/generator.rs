@@ -1,67 +1,270 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Result};
 use convert_case::{Case, Casing};
+use tera::{Context, Tera, Value};
 use crate::csv::{ColorRecord, PartCategoryRecord, ThemeRecord};
 
-const DO_NOT_EDIT: &str = "// Auto-generated by briq-utils - DO NOT EDIT";
+pub const TEMPLATES_DIR: &str = "templates";
 
-pub fn part_categories(part_categories: &[PartCategoryRecord]) -> String {
-    let mut lines = vec![
-        String::from(DO_NOT_EDIT),
-        String::from(""),
-        String::from("enum PartCategory: Int {"),
-    ];
-    for cat in part_categories.iter() {
-        lines.push(format!("   case {} = {}", sanitize_and_case(&cat.name), cat.id))
+const TEMPLATE_EXTENSION: &str = ".tera";
+
+// the output files, and their templates unless the working directory has its own
+static DEFAULTS: [(&str, &str); 3] = [
+    ("PartCategories.swift", include_str!("../templates/PartCategories.swift.tera")),
+    ("PartColors.swift", include_str!("../templates/PartColors.swift.tera")),
+    ("Themes.swift", include_str!("../templates/Themes.swift.tera")),
+];
+
+/// The templates of the generated files, by name of output file.
+pub struct Templates {
+    tera: Tera,
+    outputs: Vec<String>,
+}
+
+impl Templates {
+    /// Names of the files generated by the templates.
+    pub fn outputs(&self) -> &[String] {
+        &self.outputs
+    }
+
+    pub fn render(&self, output: &str, context: &Context) -> Result<String> {
+        // the error of Tera only names the template, the cause is in its source
+        self.tera.render(&format!("{}{}", output, TEMPLATE_EXTENSION), context)
+            .map_err(|err| anyhow!("{}", error_chain(&err)))
     }
-    lines.push(String::from("}"));
-    lines.join("\n")
 }
 
-pub fn colors(colors: &Vec<ColorRecord>) -> String {
-    let mut lines = vec![
-        String::from(DO_NOT_EDIT),
-        String::from(""),
-        String::from("let AllPartColors: [PartColor] = ["),
-    ];
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(&format!(": {}", err));
+        source = err.source();
+    }
+    message
+}
+
+fn swift_string_filter(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    match value {
+        Value::String(s) => Ok(Value::String(swift_string(s))),
+        other => Err(tera::Error::msg(format!("swift_string expects a string, got {}", other))),
+    }
+}
 
-    for color in colors {
-        let y1 = if let Some(y) = color.y1 { format!("{}", y) } else { String::from("nil") };
-        let y2 = if let Some(y) = color.y2 { format!("{}", y) } else { String::from("nil") };
-        lines.push(format!("    PartColor(name:\"{}\", rgb: \"{}\", isTransparent: {}, partsCount: {}, setsCount: {}, year1: {}, year2: {}),",
-                color.name, color.rgb, color.is_trans.to_lowercase(), color.num_parts, color.num_sets, y1, y2)); 
+/// Loads the templates of `<workdir>/templates`, or the built-in ones for the files it has no
+/// `<file>.tera` for. Its other `.tera` files generate files too, except those starting with `_`
+/// which are only there to be included or extended.
+pub fn load_templates(workdir: &Path) -> Result<Templates> {
+    let dir = workdir.join(TEMPLATES_DIR);
+    let mut templates: Vec<(String, String)> = DEFAULTS.iter()
+        .map(|(output, template)| (format!("{}{}", output, TEMPLATE_EXTENSION), template.to_string()))
+        .collect();
+    if dir.is_dir() {
+        let mut names = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if name.ends_with(TEMPLATE_EXTENSION) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        for name in names {
+            let content = fs::read_to_string(dir.join(&name))?;
+            match templates.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, template)) => *template = content,
+                None => templates.push((name, content)),
+            }
+        }
     }
-    lines.push(String::from("];"));
-    lines.join("\n")
+    let mut tera = Tera::default();
+    tera.autoescape_on(vec![]);
+    tera.register_filter("swift_string", swift_string_filter);
+    tera.add_raw_templates(templates.iter().map(|(name, content)| (name.as_str(), content.as_str())))
+        .map_err(|err| anyhow!("{}: {}", dir.display(), error_chain(&err)))?;
+    let outputs = templates.iter()
+        .filter(|(name, _)| !name.starts_with('_'))
+        .map(|(name, _)| name.trim_end_matches(TEMPLATE_EXTENSION).to_string())
+        .collect();
+    Ok(Templates { tera, outputs })
+}
+
+#[derive(serde::Serialize)]
+struct PartCategory<'a> {
+    id: u32,
+    name: &'a str,
+    // the identifier of the enum case, with backticks if it is a keyword
+    case: String,
 }
 
+#[derive(serde::Serialize)]
+struct PartColor<'a> {
+    id: i32,
+    name: &'a str,
+    rgb: String,
+    red: u8,
+    green: u8,
+    blue: u8,
+    is_transparent: bool,
+    parts_count: u32,
+    sets_count: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    year1: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    year2: Option<u16>,
+}
+
+// not used yet
+#[allow(dead_code)]
 #[derive(Debug, serde::Serialize)]
 pub struct ThemeNode {
     pub id: u32,
-    pub children: Vec<u32>, 
+    pub children: Vec<u32>,
 }
 
-pub fn themes(themes: &Vec<ThemeRecord>) -> String {
-    let mut lines = vec![
-        String::from(DO_NOT_EDIT), 
-        String::from("\nlet AllThemes: [Theme] = ["),
-    ];
-    for theme in themes {
-        lines.push(format!("    Theme(id: {}, name: \"{}\"),", theme.id, theme.name))
-    }
-    lines.push(String::from("]\n\nlet ThemesTree: [Theme] = ["));
-    for theme in themes {
-        if theme.parent_id.is_none() {
-            lines.push(format!("    AllThemes[{}],", theme.id));
+#[derive(serde::Serialize)]
+struct Theme<'a> {
+    id: u32,
+    name: &'a str,
+    // IDs are stable across regenerations and can have holes, so themes can refer to each other by
+    // position in the list instead
+    position: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_position: Option<usize>,
+}
+
+/// The context of the templates: the `part_categories`, `colors` and `themes`. Also returns the
+/// adjustments made to the names of the part categories to get valid and unique Swift identifiers.
+pub fn context(part_categories: &[PartCategoryRecord], colors: &[ColorRecord], themes: &[ThemeRecord]) -> (Context, Vec<String>) {
+    let (cases, adjustments) = part_category_cases(part_categories);
+    let part_categories: Vec<PartCategory> = part_categories.iter()
+        .map(|cat| PartCategory { id: cat.id, name: &cat.name, case: escape_keyword(&cases[&cat.id]) })
+        .collect();
+    let colors: Vec<PartColor> = colors.iter()
+        .map(|color| PartColor {
+            id: color.id,
+            name: &color.name,
+            rgb: color.rgb.to_string(),
+            red: color.rgb.red,
+            green: color.rgb.green,
+            blue: color.rgb.blue,
+            is_transparent: color.is_trans,
+            parts_count: color.num_parts,
+            sets_count: color.num_sets,
+            year1: color.y1,
+            year2: color.y2,
+        })
+        .collect();
+    let positions: HashMap<u32, usize> = themes.iter().enumerate().map(|(i, t)| (t.id, i)).collect();
+    let themes: Vec<Theme> = themes.iter().enumerate()
+        .map(|(i, theme)| Theme {
+            id: theme.id,
+            name: &theme.name,
+            position: i,
+            parent_id: theme.parent_id,
+            parent_position: theme.parent_id.map(|id| positions[&id]),
+        })
+        .collect();
+    let mut context = Context::new();
+    context.insert("part_categories", &part_categories);
+    context.insert("colors", &colors);
+    context.insert("themes", &themes);
+    (context, adjustments)
+}
+
+// The names of the `PartCategory` cases by category ID, and the adjustments made to get valid and
+// unique Swift identifiers.
+fn part_category_cases(part_categories: &[PartCategoryRecord]) -> (HashMap<u32, String>, Vec<String>) {
+    let mut adjustments = vec![];
+    // sorted by ID, so the same category keeps its name when new ones are added
+    let mut sorted: Vec<&PartCategoryRecord> = part_categories.iter().collect();
+    sorted.sort_by_key(|c| c.id);
+    let mut taken: HashMap<String, u32> = HashMap::new();
+    let mut cases: HashMap<u32, String> = HashMap::new();
+    for cat in sorted {
+        let (mut identifier, notes) = swift_identifier(&sanitize_and_case(&cat.name), &format!("category{}", cat.id));
+        for note in notes {
+            adjustments.push(format!("part category {} \"{}\": {}", cat.id, cat.name, note));
         }
+        if let Some(other) = taken.get(&identifier) {
+            let base = identifier.clone();
+            // camel-cased names have no underscores, so this can't take the name of another category
+            let mut suffix = format!("_{}", cat.id);
+            while taken.contains_key(&format!("{}{}", base, suffix)) {
+                suffix.push('_');
+            }
+            identifier = format!("{}{}", base, suffix);
+            adjustments.push(format!("part category {} \"{}\": {} already names part category {}, renamed {}",
+                cat.id, cat.name, base, other, identifier));
+        }
+        if SWIFT_KEYWORDS.contains(&identifier.as_str()) {
+            adjustments.push(format!("part category {} \"{}\": {} is a Swift keyword, escaped with backticks",
+                cat.id, cat.name, identifier));
+        }
+        taken.insert(identifier.clone(), cat.id);
+        cases.insert(cat.id, identifier);
+    }
+    (cases, adjustments)
+}
+
+// Reserved words of Swift, which need backticks to be used as identifiers
+const SWIFT_KEYWORDS: [&str; 55] = [
+    "Any", "Self", "as", "associatedtype", "await", "break", "case", "catch", "class", "continue",
+    "default", "defer", "deinit", "do", "else", "enum", "extension", "fallthrough", "false",
+    "fileprivate", "for", "func", "guard", "if", "import", "in", "init", "inout", "internal", "is",
+    "let", "nil", "open", "operator", "private", "precedencegroup", "protocol", "public", "repeat",
+    "rethrows", "return", "self", "static", "struct", "subscript", "super", "switch", "throw", "throws",
+    "true", "try", "typealias", "var", "where", "while",
+];
+
+fn escape_keyword(identifier: &str) -> String {
+    if SWIFT_KEYWORDS.contains(&identifier) {
+        format!("`{}`", identifier)
+    } else {
+        identifier.to_string()
+    }
+}
+
+// Makes `name` a valid Swift identifier, or `fallback` if nothing is left of it. Keywords are
+// escaped when written, as the backticks are not part of the identifier.
+fn swift_identifier(name: &str, fallback: &str) -> (String, Vec<String>) {
+    let mut notes = vec![];
+    let mut identifier: String = name.chars().filter(|c| c.is_alphanumeric() || *c == '_').collect();
+    if identifier != name {
+        notes.push(format!("invalid characters removed from {}", name));
+    }
+    if identifier.is_empty() {
+        notes.push(format!("no valid characters, named {}", fallback));
+        identifier = fallback.to_string();
+    }
+    if identifier.starts_with(|c: char| c.is_ascii_digit()) {
+        notes.push(format!("{} starts with a digit, prefixed with _", identifier));
+        identifier.insert(0, '_');
     }
-    lines.push(String::from("].sorted { $0.name < $1.name };\n\npublic func initThemesTree() {"));
-    for theme in themes {
-        if let Some(parent_id) = theme.parent_id {
-            lines.push(format!("    AllThemes[{}].parent = AllThemes[{}]", theme.id, parent_id));
-            lines.push(format!("    AllThemes[{}].children.append(AllThemes[{}])", parent_id, theme.id))
+    (identifier, notes)
+}
+
+/// Swift string literal of `s`, with the characters that can't appear as is escaped.
+pub fn swift_string(s: &str) -> String {
+    let mut literal = String::with_capacity(s.len() + 2);
+    literal.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            '\0' => literal.push_str("\\0"),
+            c if c.is_control() => literal.push_str(&format!("\\u{{{:X}}}", c as u32)),
+            c => literal.push(c),
         }
     }
-    lines.push(String::from("}"));
-    lines.join("\n")
+    literal.push('"');
+    literal
 }
 
 // pub fn sets(sets: &Vec<Set>) -> String {
@@ -70,7 +273,7 @@ pub fn themes(themes: &Vec<ThemeRecord>) -> String {
 //         String::from("\nlet allSets: [Set] = ["),
 //     ];
 //     for set in sets {
-//         lines.push(format!("    Set(number: \"{}\", name: \"{}\"),", set.number, set.name.replace("\"", "\\\"")));
+//         lines.push(format!("    Set(number: {}, name: {}),", swift_string(&set.number), swift_string(&set.name)));
 //     }
 //     lines.push(String::from("]"));
 //     lines.join("\n")
@@ -107,3 +310,96 @@ pub fn sanitize_and_case(s: &str) -> String {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::csv::{ColorRecord, PartCategoryRecord, Rgb, ThemeRecord};
+    use super::*;
+
+    #[test]
+    fn swift_string_escapes_quotes() {
+        assert_eq!(swift_string("Technic \"Pro\""), r#""Technic \"Pro\"""#);
+    }
+
+    #[test]
+    fn swift_string_escapes_backslashes() {
+        assert_eq!(swift_string(r"Back\slash"), r#""Back\\slash""#);
+    }
+
+    #[test]
+    fn swift_string_escapes_newlines() {
+        assert_eq!(swift_string("Line\nbreak"), r#""Line\nbreak""#);
+    }
+
+    #[test]
+    fn swift_string_escapes_carriage_returns() {
+        assert_eq!(swift_string("a\rb"), r#""a\rb""#);
+    }
+
+    #[test]
+    fn swift_string_escapes_tabs() {
+        assert_eq!(swift_string("a\tb"), r#""a\tb""#);
+    }
+
+    #[test]
+    fn swift_string_escapes_nul() {
+        assert_eq!(swift_string("a\0b"), r#""a\0b""#);
+    }
+
+    #[test]
+    fn swift_string_escapes_other_control_characters() {
+        assert_eq!(swift_string("a\u{1b}b"), r#""a\u{1B}b""#);
+    }
+
+    #[test]
+    fn swift_string_keeps_unicode() {
+        assert_eq!(swift_string("Légo 東京 🚀"), "\"Légo 東京 🚀\"");
+    }
+
+    fn category_cases(names: &[(u32, &str)]) -> Vec<String> {
+        let categories: Vec<PartCategoryRecord> = names.iter()
+            .map(|(id, name)| PartCategoryRecord { id: *id, name: name.to_string() })
+            .collect();
+        let (cases, _) = part_category_cases(&categories);
+        categories.iter().map(|c| escape_keyword(&cases[&c.id])).collect()
+    }
+
+    #[test]
+    fn category_cases_disambiguate_collisions() {
+        assert_eq!(category_cases(&[(1, "Bricks"), (2, "Bricks."), (4, "bricks2")]), ["bricks", "bricks_2", "bricks2"]);
+    }
+
+    #[test]
+    fn category_cases_escape_keywords() {
+        assert_eq!(category_cases(&[(1, "Default"), (2, "Return")]), ["`default`", "`return`"]);
+    }
+
+    #[test]
+    fn category_cases_prefix_leading_digits() {
+        assert_eq!(category_cases(&[(1, "2x2 Plates")]), ["_2X2Plates"]);
+    }
+
+    #[test]
+    fn renders_hostile_names() {
+        let name = "\"); exit(1) //\\\n";
+        let colors = vec![ColorRecord {
+            id: 1,
+            name: name.to_string(),
+            rgb: Rgb { red: 0x05, green: 0x13, blue: 0x1D },
+            is_trans: false,
+            num_parts: 10,
+            num_sets: 2,
+            y1: Some(1957),
+            y2: None,
+        }];
+        let themes = vec![ThemeRecord { id: 1, name: name.to_string(), parent_id: None }];
+        let templates = load_templates(Path::new("/nonexistent")).unwrap();
+        let (context, _) = context(&[], &colors, &themes);
+        let escaped = r#""\"); exit(1) //\\\n""#;
+        let part_colors = templates.render("PartColors.swift", &context).unwrap();
+        assert!(part_colors.contains(&format!("    PartColor(id: 1, name:{}, rgb: 0x05131D, isTransparent: false, partsCount: 10, setsCount: 2, year1: 1957, year2: nil),", escaped)));
+        let themes = templates.render("Themes.swift", &context).unwrap();
+        assert!(themes.contains(&format!("    Theme(id: 1, name: {}),", escaped)));
+    }
+}
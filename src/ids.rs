@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Result};
+
+pub const FILE_NAME: &str = "ids.json";
+
+/// Rebrickable IDs to the dense IDs used by BRIQ, kept across regenerations so that the IDs
+/// already persisted by the app don't shift when Rebrickable inserts a color or a theme.
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct IdMappings {
+    #[serde(default)]
+    pub colors: BTreeMap<i32, i32>,
+    #[serde(default)]
+    pub themes: BTreeMap<u32, u32>,
+}
+
+impl IdMappings {
+    /// Gives the next free ID to each color not mapped yet, in order.
+    pub fn assign_colors(&mut self, ids: impl Iterator<Item = i32>) {
+        let mut next = self.colors.values().max().map_or(0, |max| max + 1);
+        for id in ids {
+            self.colors.entry(id).or_insert_with(|| {
+                next += 1;
+                next - 1
+            });
+        }
+    }
+
+    /// Gives the next free ID to each theme not mapped yet, in order.
+    pub fn assign_themes(&mut self, ids: impl Iterator<Item = u32>) {
+        let mut next = self.themes.values().max().map_or(0, |max| max + 1);
+        for id in ids {
+            self.themes.entry(id).or_insert_with(|| {
+                next += 1;
+                next - 1
+            });
+        }
+    }
+}
+
+/// Loads `ids.json` from the working directory, or empty mappings if there is none.
+pub fn load(workdir: &Path) -> Result<IdMappings> {
+    let path = workdir.join(FILE_NAME);
+    if path.exists() {
+        let content = fs::read_to_string(&path)?;
+        serde_json::from_str(&content).map_err(|err| anyhow!("{}: {}", path.display(), err))
+    } else {
+        Ok(IdMappings::default())
+    }
+}
+
+pub fn save(mappings: &IdMappings, workdir: &Path) -> Result<()> {
+    let json_string = serde_json::to_string_pretty(mappings)?;
+    fs::write(workdir.join(FILE_NAME), json_string)?;
+    Ok(())
+}
@@ -1,13 +1,22 @@
 use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::{PathBuf};
-use anyhow::{Result};
-use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
+mod assets;
 mod cache;
 mod csv;
+mod diff;
+mod findings;
 mod generator;
+mod ids;
+mod manifest;
 mod model;
+mod overrides;
+mod serve;
+mod sqlite;
+mod thumbnails;
 mod utils;
 
 use utils::pluralize as plrze;
@@ -18,6 +27,38 @@ use utils::pluralize as plrze;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Skip CSV rows that fail to parse and report them, instead of aborting on the first one
+    #[arg(long, global = true)]
+    skip_bad_rows: bool,
+    /// What to do with the rows referencing a color or theme that doesn't exist [default: abort, or
+    /// placeholder for analyze, which reports them]
+    #[arg(long, global = true, value_enum)]
+    on_missing_reference: Option<csv::Policy>,
+}
+
+impl Cli {
+    fn policy(&self) -> csv::Policy {
+        match (&self.command, self.on_missing_reference) {
+            (_, Some(policy)) => policy,
+            // the findings of analyze list them, so keep going to report everything
+            (Commands::Analyze { .. }, None) => csv::Policy::Placeholder,
+            (_, None) => csv::Policy::Abort,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    /// init.json
+    Json,
+    /// init.sqlite, with every version of the sets
+    Sqlite,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportFormat {
+    Text,
+    Json,
 }
 
 #[derive(Subcommand)]
@@ -27,6 +68,12 @@ enum Commands {
         /// Path to the working directory
         #[arg(short, long)]
         workdir: String,
+        /// Which versions of the sets inventories to write in init.json
+        #[arg(long, value_enum, default_value_t = model::Versions::Last)]
+        versions: model::Versions,
+        /// Format of the dataset
+        #[arg(long, value_enum, default_value_t = Format::Json)]
+        format: Format,
     },
     /// Analyze the data from Rebrickable
     Analyze {
@@ -34,7 +81,32 @@ enum Commands {
         #[arg(short, long)]
         workdir: String,
         #[arg(short, long)]
-        set: Option<String>
+        set: Option<String>,
+        /// Format of the validation report. JSON needs --report, stdout has the progress too
+        #[arg(long, value_enum, default_value_t = ReportFormat::Text, requires_if("json", "report"))]
+        report_format: ReportFormat,
+        /// Write the validation report to this file instead of stdout
+        #[arg(long)]
+        report: Option<String>,
+        /// Exit with an error if there are more validation errors than this
+        #[arg(long)]
+        max_errors: Option<usize>,
+        /// Exit with an error if there are more validation warnings than this
+        #[arg(long)]
+        max_warnings: Option<usize>,
+    },
+    /// Compute the changes between two datasets, each either a working directory or an init.json file
+    Diff {
+        /// Path to the old working directory or init.json
+        old: String,
+        /// Path to the new working directory or init.json
+        new: String,
+        /// Path of the delta JSON file to write
+        #[arg(short, long)]
+        output: String,
+        /// Which versions of the sets inventories to compare, when reading a working directory
+        #[arg(long, value_enum, default_value_t = model::Versions::Last)]
+        versions: model::Versions,
     },
     /// Mirror remote data into a local cache and working dir
     Mirror {
@@ -44,33 +116,134 @@ enum Commands {
         /// Path to the working directory
         #[arg(short, long)]
         workdir: String,
-    }
+        /// Number of concurrent downloads
+        #[arg(short, long, default_value_t = 8)]
+        jobs: usize,
+        /// Maximum number of requests per second to a same host
+        #[arg(long)]
+        rate: Option<f64>,
+        /// Number of retries after a transient failure (network error, 429 or 5xx status)
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
+        #[command(flatten)]
+        filter: MirrorFilter,
+        #[command(subcommand)]
+        action: Option<MirrorAction>,
+    },
+    /// Serve the mirror cache over HTTP, at the paths of the rewritten asset URLs
+    Serve {
+        /// Path to the cache directory
+        #[arg(short, long)]
+        cache: String,
+        /// Path to the working directory, for its assets.toml
+        #[arg(short, long)]
+        workdir: String,
+        /// Address and port to listen on
+        #[arg(short, long, default_value = "127.0.0.1:8080")]
+        address: String,
+        /// Number of requests served concurrently
+        #[arg(short, long, default_value_t = 4)]
+        jobs: usize,
+        /// Append the paths not found to this file
+        #[arg(long)]
+        not_found_log: Option<String>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AssetKind {
+    Sets,
+    Minifigs,
+    Parts,
+}
+
+#[derive(Args)]
+struct MirrorFilter {
+    /// Only the assets of the sets of these Rebrickable theme IDs, including their sub-themes
+    #[arg(long = "theme", value_delimiter = ',')]
+    themes: Vec<u32>,
+    /// Only the assets of the sets released from this year
+    #[arg(long)]
+    from_year: Option<u16>,
+    /// Only the assets of the sets released until this year
+    #[arg(long)]
+    to_year: Option<u16>,
+    /// Only these kinds of assets
+    #[arg(long = "kind", value_enum, value_delimiter = ',')]
+    kinds: Vec<AssetKind>,
+}
+
+#[derive(Subcommand)]
+enum MirrorAction {
+    /// Check the cached files against the manifest, and download again the missing or bad ones
+    Verify,
+    /// Revalidate the cached files with the server, and download again the ones that changed
+    Refresh,
+    /// Remove the cached files no longer referenced by the data
+    Prune {
+        /// Only report the files that would be removed
+        #[arg(long)]
+        dry_run: bool,
+        /// Move the files to this directory instead of removing them
+        #[arg(long)]
+        quarantine: Option<String>,
+    },
+    /// Generate thumbnails of the cached files, at the paths of their asset URLs
+    Thumbnails {
+        /// Directory of the thumbnails, with a subdirectory per size
+        #[arg(short, long)]
+        output: String,
+        /// Maximum width and height of the thumbnails, in pixels
+        #[arg(long = "size", value_delimiter = ',', default_value = "128")]
+        sizes: Vec<u32>,
+        /// Also write a lossy WebP copy of every thumbnail
+        #[arg(long)]
+        webp: bool,
+        /// Quality of the WebP copies, from 0 to 100
+        #[arg(long, default_value_t = 80.0, value_parser = parse_quality)]
+        webp_quality: f32,
+    },
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Generate { workdir } => {
+        Commands::Generate { workdir, versions, format } => {
             println!("Reading all CSV data...");
-            match csv::read_all(workdir) {
+            match csv::read_all(workdir, cli.skip_bad_rows, cli.policy()) {
                 Ok(data) => {
+                    report_bad_rows(&data.parse_errors);
                     let workdir: PathBuf = workdir.into();
                     println!("Generating Swift code...");
-                    let part_cats = generator::part_categories(&data.part_categories);
-                    fs::write(workdir.join("PartCategories.swift"), part_cats)?;
-                    let part_colors = generator::colors(&data.colors);
-                    fs::write(workdir.join("PartColors.swift"), part_colors)?;
-                    let themes = generator::themes(&data.themes);
-                    fs::write(workdir.join("Themes.swift"), themes)?;
+                    let templates = generator::load_templates(&workdir)?;
+                    let (context, adjustments) = generator::context(&data.part_categories, &data.colors, &data.themes);
+                    for adjustment in adjustments {
+                        eprintln!("PartCategories.swift: {}", adjustment);
+                    }
+                    for output in templates.outputs() {
+                        fs::write(workdir.join(output), templates.render(output, &context)?)?;
+                    }
+                    let overrides = load_overrides(&workdir, &data.sets)?;
+                    ids::save(&data.id_mappings, &workdir)?;
                     println!("Converting data to BRIQ model...");
-                    let data = model::convert(*data);
+                    let assets = assets::load(&workdir)?;
+                    let data = model::convert(*data, &overrides, &assets, *versions)?;
+                    report_unmatched_urls(&assets);
                     // println!("Generating more Swift code...");
                     // let sets = generator::sets(&data.sets);
                     // fs::write(workdir.join("Sets.swift"), sets)?;
-                    println!("Generating JSON...");
-                    let json_string = serde_json::to_string_pretty(&data)?;
-                    fs::write(workdir.join("init.json"), json_string)?;
+                    match format {
+                        Format::Json => {
+                            println!("Generating JSON...");
+                            let json_string = serde_json::to_string_pretty(&data)?;
+                            fs::write(workdir.join("init.json"), json_string)?;
+                        }
+                        Format::Sqlite => {
+                            println!("Generating SQLite database...");
+                            sqlite::write(&data, &workdir.join("init.sqlite"))?;
+                        }
+                    }
                 }
                 Err(err) => {
                     eprintln!("{}", err);
@@ -79,19 +252,33 @@ fn main() -> Result<()> {
             }
             Ok(())
         }
-        Commands::Analyze { workdir, set } => {
+        Commands::Analyze { workdir, set, report_format, report, max_errors, max_warnings } => {
             println!("Reading all CSV data...");
-            match csv::read_all(workdir) {
-                Ok(data) => {
+            match csv::read_all(workdir, cli.skip_bad_rows, cli.policy()) {
+                Ok(mut data) => {
                     println!("Validating data...");
-                    csv::validate(&data);
+                    let overrides = overrides::load(Path::new(workdir))?;
+                    // the rows skipped by --skip-bad-rows are reported, and counted, as errors
+                    let mut findings: Vec<findings::Finding> = data.parse_errors.iter().map(csv::ParseError::to_finding).collect();
+                    findings.append(&mut data.findings);
+                    findings.extend(overrides.check(&data.sets));
+                    let content = match report_format {
+                        ReportFormat::Text => findings::to_text(&findings),
+                        ReportFormat::Json => findings::to_json(&findings)?,
+                    };
+                    match report {
+                        Some(report) => fs::write(report, content)?,
+                        None => print!("{}", content),
+                    }
                     println!("Themes tree has a max depth of {}", get_themes_tree_depth(&data.themes));
                     let materials = data.parts.iter().map(|p|&p.part_material);
                     let materials: HashSet<_> = materials.into_iter().collect();
                     println!("There are {} unique parts materials.", materials.len());
 
                     println!("Converting data to BRIQ model...");
-                    let data = model::convert(*data);
+                    let assets = assets::load(Path::new(workdir))?;
+                    let data = model::convert(*data, &overrides, &assets, model::Versions::Last)?;
+                    report_unmatched_urls(&assets);
                     println!("Analyzing data...");
                     let mut count = 0;
                     let mut count2 = 0;
@@ -103,8 +290,7 @@ fn main() -> Result<()> {
                                 count2 += 1
                             }
                         }
-                        let last = set.versions.last().unwrap();
-                        let total: u16 = last.parts.iter().map(|x| x.quantity).sum();
+                        let total: u16 = set.versions.last().map_or(0, |last| last.parts.iter().map(|x| x.quantity).sum());
                         if (set.parts_count as u16) != total {
                             //eprintln!("Set {}: parts_count={} parts.len={}", set.number, set.parts_count, total);
                             count3 += 1;
@@ -123,11 +309,18 @@ fn main() -> Result<()> {
                             println!("Set {} has {}:", set.number, plrze(set.versions.len(), "version"));
                             let mut all_parts: Vec<Vec<model::SetPart>> = Vec::new();
                             for (i, version) in set.versions.iter().enumerate() {
-                                println!("Version #{}: {}, {}.", i, plrze(version.minifigs.len(), "minifig"), plrze(version.parts.len(), "part"));
+                                println!("Version #{}: {}, {}, {}.", i, plrze(version.minifigs.len(), "minifig"), plrze(version.parts.len(), "part"),
+                                    plrze(version.spare_parts.len(), "spare part"));
                                 all_parts.push(version.parts.clone());
                                 for m in &version.minifigs {
                                     println!("- {:?}", m);
                                 }
+                                if !version.spare_parts.is_empty() {
+                                    println!("Spare parts of version {i}:");
+                                    for p in &version.spare_parts {
+                                        println!("- {:?}", p);
+                                    }
+                                }
                             }
                             let mut parts = process(all_parts.clone());
                             let common = parts.pop().unwrap();
@@ -145,6 +338,7 @@ fn main() -> Result<()> {
                             eprintln!("Invalid set: {}", number)
                         }
                     }
+                    check_thresholds(&findings, *max_errors, *max_warnings)?;
                 }
                 Err(err) => {
                     eprintln!("{}", err);
@@ -153,30 +347,78 @@ fn main() -> Result<()> {
             }
             Ok(())
         },
-        Commands::Mirror { cache, workdir } => {
+        Commands::Diff { old, new, output, versions } => {
+            let (old_data, id_mappings) = load_dataset(old, cli.skip_bad_rows, cli.policy(), *versions, None)?;
+            if id_mappings.is_none() && Path::new(new).is_dir() {
+                return Err(anyhow!("{}: no {} next to it to give the colors and themes of {} the same IDs",
+                    old, ids::FILE_NAME, new));
+            }
+            let (new_data, _) = load_dataset(new, cli.skip_bad_rows, cli.policy(), *versions, id_mappings)?;
+            println!("Computing changes...");
+            let (delta, summaries) = diff::diff(&old_data, &new_data);
+            for s in summaries {
+                println!("{}: {} added, {} removed, {} changed.", s.collection, s.added, s.removed, s.changed);
+            }
+            fs::write(output, serde_json::to_string_pretty(&delta)?)?;
+            Ok(())
+        }
+        Commands::Serve { cache, workdir, address, jobs, not_found_log } => {
+            let assets = assets::load(Path::new(workdir))?;
+            let options = serve::Options {
+                address: address.clone(),
+                jobs: *jobs,
+                not_found_log: not_found_log.as_ref().map(PathBuf::from),
+            };
+            serve::serve(Path::new(cache), &assets, &options)
+        }
+        Commands::Mirror { cache, workdir, jobs, rate, retries, filter, action } => {
             println!("Reading all CSV data...");
-            match csv::read_all(workdir) {
+            match csv::read_all(workdir, cli.skip_bad_rows, cli.policy()) {
                 Ok(data) => {
-                    let mut urls: Vec<String> = data.inventories_parts.iter()
-                        .filter(|p| !p.img_url.is_empty())
-                        .map(|p| p.img_url.clone()).collect();
-                    urls.extend(data.sets.iter()
-                        .filter(|s| !s.img_url.is_empty())
-                        .map(|s| s.img_url.clone()));
-                    urls.extend(data.minifigs.iter()
-                        .filter(|m| !m.img_url.is_empty())
-                        .map(|m| m.img_url.clone()));
-                    urls.sort();
-                    urls.dedup();
-                    let total = urls.len();
-                    let mut i = 0;
-                    for url in urls {
-                        i += 1;
-                        print!("{:.2}% ", (i as f64 / total as f64) * 100.0);
-                        if let Err(err) = cache::mirror(&url, cache) {
-                            eprintln!("\x1b[31m{} {}\x1b[0m", url, err);
-                        }
-                    } 
+                    report_bad_rows(&data.parse_errors);
+                    // prune keeps the files of the URLs, so filtering them would remove the others
+                    if matches!(action, Some(MirrorAction::Prune { .. })) && !filter.is_empty() {
+                        return Err(anyhow!("prune can't be filtered, it would remove the images outside of the filters"));
+                    }
+                    let urls = filter.urls(&data)?;
+                    let options = cache::Options { jobs: *jobs, rate: *rate, retries: *retries };
+                    let mirror = cache::Mirror::new(Path::new(cache), &options)?;
+                    // the manifest keeps what was recorded even if the action failed
+                    let run = || -> Result<usize> {
+                        Ok(match action {
+                            None => mirror.mirror_all(&urls, false),
+                            Some(MirrorAction::Refresh) => mirror.mirror_all(&urls, true),
+                            Some(MirrorAction::Prune { dry_run, quarantine }) => {
+                                let quarantine = quarantine.as_ref().map(Path::new);
+                                let (count, size) = mirror.prune(&urls, quarantine, *dry_run)?;
+                                let verb = if *dry_run { "would be reclaimed" } else { "reclaimed" };
+                                println!("{}, {} {}.", plrze(count, "stale file"), utils::format_size(size), verb);
+                                0
+                            }
+                            Some(MirrorAction::Thumbnails { output, sizes, webp, webp_quality }) => {
+                                let assets = assets::load(Path::new(workdir))?;
+                                let options = thumbnails::Options { sizes: sizes.clone(), webp: *webp, webp_quality: *webp_quality };
+                                let failed = thumbnails::generate_all(&mirror, &urls, Path::new(cache), Path::new(output), &assets, &options);
+                                if failed > 0 {
+                                    eprintln!("{} could not be generated.", plrze(failed, "thumbnail"));
+                                }
+                                0
+                            }
+                            Some(MirrorAction::Verify) => {
+                                println!("Verifying cached files...");
+                                let problems = mirror.verify(&urls);
+                                println!("{} to download again.", plrze(problems.len(), "URL"));
+                                let urls: Vec<String> = problems.into_iter().map(|(url, _)| url).collect();
+                                mirror.mirror_all(&urls, false)
+                            }
+                        })
+                    };
+                    let result = run();
+                    mirror.save_manifest()?;
+                    let failed = result?;
+                    if failed > 0 {
+                        eprintln!("{} could not be mirrored.", plrze(failed, "URL"));
+                    }
                     Ok(())
                 }
                 Err(err) => {
@@ -188,6 +430,172 @@ fn main() -> Result<()> {
     }    
 }
 
+impl MirrorFilter {
+    fn is_empty(&self) -> bool {
+        self.themes.is_empty() && self.from_year.is_none() && self.to_year.is_none() && self.kinds.is_empty()
+    }
+
+    fn wants(&self, kind: AssetKind) -> bool {
+        self.kinds.is_empty() || self.kinds.contains(&kind)
+    }
+
+    // Themes are normalized when read, so the Rebrickable IDs are mapped to ours first
+    fn theme_ids(&self, data: &csv::Data) -> Result<HashSet<u32>> {
+        let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+        for theme in &data.themes {
+            if let Some(parent_id) = theme.parent_id {
+                children.entry(parent_id).or_default().push(theme.id);
+            }
+        }
+        let mut ids = HashSet::new();
+        let mut queue = vec![];
+        for id in &self.themes {
+            match data.id_mappings.themes.get(id) {
+                Some(id) => queue.push(*id),
+                None => return Err(anyhow!("unknown theme: {}", id)),
+            }
+        }
+        while let Some(id) = queue.pop() {
+            if ids.insert(id) {
+                queue.extend(children.get(&id).into_iter().flatten());
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Image URLs of the sets, minifigs and parts selected by the filter.
+    fn urls(&self, data: &csv::Data) -> Result<Vec<String>> {
+        let themes = self.theme_ids(data)?;
+        let sets: Vec<&csv::SetRecord> = data.sets.iter()
+            .filter(|s| themes.is_empty() || themes.contains(&s.theme_id))
+            .filter(|s| self.from_year.is_none_or(|y| s.year >= y))
+            .filter(|s| self.to_year.is_none_or(|y| s.year <= y))
+            .collect();
+        let mut urls: Vec<String> = vec![];
+        if self.wants(AssetKind::Sets) {
+            urls.extend(sets.iter().map(|s| s.img_url.clone()));
+        }
+        if self.themes.is_empty() && self.from_year.is_none() && self.to_year.is_none() {
+            if self.wants(AssetKind::Minifigs) {
+                urls.extend(data.minifigs.iter().map(|m| m.img_url.clone()));
+            }
+            if self.wants(AssetKind::Parts) {
+                urls.extend(data.inventories_parts.iter().map(|p| p.img_url.clone()));
+            }
+        } else {
+            // only the minifigs of the sets, and the parts of those sets and minifigs
+            let set_nums: HashSet<&str> = sets.iter().map(|s| s.set_num.as_str()).collect();
+            let inventory_ids: HashSet<u32> = data.inventories.iter()
+                .filter(|i| set_nums.contains(i.set_num.as_str()))
+                .map(|i| i.id)
+                .collect();
+            let fig_nums: HashSet<&str> = data.inventories_minifigs.iter()
+                .filter(|m| inventory_ids.contains(&m.inventory_id))
+                .map(|m| m.fig_num.as_str())
+                .collect();
+            if self.wants(AssetKind::Minifigs) {
+                urls.extend(data.minifigs.iter()
+                    .filter(|m| fig_nums.contains(m.fig_num.as_str()))
+                    .map(|m| m.img_url.clone()));
+            }
+            if self.wants(AssetKind::Parts) {
+                let inventory_ids: HashSet<u32> = data.inventories.iter()
+                    .filter(|i| set_nums.contains(i.set_num.as_str()) || fig_nums.contains(i.set_num.as_str()))
+                    .map(|i| i.id)
+                    .collect();
+                urls.extend(data.inventories_parts.iter()
+                    .filter(|p| inventory_ids.contains(&p.inventory_id))
+                    .map(|p| p.img_url.clone()));
+            }
+        }
+        urls.retain(|url| !url.is_empty());
+        urls.sort();
+        urls.dedup();
+        Ok(urls)
+    }
+}
+
+// A dataset is either a working directory with the CSV data, or an init.json already generated
+// Reads a working directory, with `id_mappings` rather than its own if given, or an init.json.
+// Returns the dataset, and the mappings of its IDs if known: those of the working directory, or
+// the ids.json next to the init.json.
+fn load_dataset(path: &str, skip_bad_rows: bool, policy: csv::Policy, versions: model::Versions, id_mappings: Option<ids::IdMappings>)
+    -> Result<(serde_json::Value, Option<ids::IdMappings>)> {
+    if Path::new(path).is_dir() {
+        println!("Reading all CSV data from {}...", path);
+        let mut data = match id_mappings {
+            Some(id_mappings) => csv::read_all_with_ids(path, skip_bad_rows, policy, id_mappings)?,
+            None => csv::read_all(path, skip_bad_rows, policy)?,
+        };
+        report_bad_rows(&data.parse_errors);
+        let id_mappings = std::mem::take(&mut data.id_mappings);
+        let overrides = load_overrides(Path::new(path), &data.sets)?;
+        println!("Converting data to BRIQ model...");
+        let assets = assets::load(Path::new(path))?;
+        let data = model::convert(*data, &overrides, &assets, versions)?;
+        report_unmatched_urls(&assets);
+        Ok((serde_json::to_value(&data)?, Some(id_mappings)))
+    } else {
+        println!("Reading {}...", path);
+        let content = fs::read_to_string(path)?;
+        let ids_path = Path::new(path).with_file_name(ids::FILE_NAME);
+        let id_mappings = match ids_path.parent() {
+            Some(dir) if ids_path.exists() => Some(ids::load(dir)?),
+            _ => None,
+        };
+        Ok((serde_json::from_str(&content)?, id_mappings))
+    }
+}
+
+fn parse_quality(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(quality) if (0.0..=100.0).contains(&quality) => Ok(quality),
+        _ => Err(String::from("must be a number from 0 to 100")),
+    }
+}
+
+fn load_overrides(workdir: &Path, sets: &[csv::SetRecord]) -> Result<overrides::Overrides> {
+    let overrides = overrides::load(workdir)?;
+    for warning in overrides.check(sets) {
+        eprintln!("{}", warning);
+    }
+    Ok(overrides)
+}
+
+fn check_thresholds(findings: &[findings::Finding], max_errors: Option<usize>, max_warnings: Option<usize>) -> Result<()> {
+    for (severity, max, word) in [
+        (findings::Severity::Error, max_errors, "error"),
+        (findings::Severity::Warning, max_warnings, "warning"),
+    ] {
+        let count = findings::count(findings, severity);
+        if let Some(max) = max.filter(|max| count > *max) {
+            return Err(anyhow!("{} found, more than the maximum of {}", plrze(count, word), max));
+        }
+    }
+    Ok(())
+}
+
+fn report_bad_rows(errors: &[csv::ParseError]) {
+    if errors.is_empty() {
+        return;
+    }
+    eprintln!("Skipped {}:", plrze(errors.len(), "bad row"));
+    for err in errors {
+        eprintln!("- {}", err);
+    }
+}
+
+fn report_unmatched_urls(assets: &assets::AssetUrls) {
+    let urls = assets.unmatched();
+    if urls.is_empty() {
+        return;
+    }
+    eprintln!("Dropped {} matching no rule of {}:", plrze(urls.len(), "asset URL"), assets::FILE_NAME);
+    for url in urls {
+        eprintln!("- {}", url);
+    }
+}
+
 fn get_themes_tree_depth(themes: &[csv::ThemeRecord]) -> u32 {
     let mut m: HashMap<u32, &csv::ThemeRecord> = HashMap::new();
     for theme in themes {
@@ -199,10 +607,14 @@ fn get_themes_tree_depth(themes: &[csv::ThemeRecord]) -> u32 {
         let mut th = theme;
         loop {
             count += 1;
-            if th.parent_id.is_none() {
+            // a cycle in the parents, reported by the validation
+            if count as usize > themes.len() {
                 break
             }
-            th = m[&th.parent_id.unwrap()]
+            match th.parent_id.and_then(|id| m.get(&id)) {
+                Some(parent) => th = parent,
+                None => break,
+            }
         }
         if count > max {
             max = count
@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+pub const FILE_NAME: &str = "manifest.json";
+
+/// What was downloaded for a URL of the mirror cache.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Entry {
+    /// Path of the file, relative to the cache directory
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    pub content_type: Option<String>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// Seconds since the Unix epoch
+    pub fetched_at: u64,
+}
+
+/// Entries of the mirror cache by URL, stored as `manifest.json` in the cache directory.
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct Manifest {
+    pub entries: BTreeMap<String, Entry>,
+}
+
+impl Manifest {
+    pub fn load(cache_dir: &Path) -> Result<Self> {
+        let path = cache_dir.join(FILE_NAME);
+        if path.exists() {
+            let content = fs::read_to_string(&path)?;
+            serde_json::from_str(&content).map_err(|err| anyhow!("{}: {}", path.display(), err))
+        } else {
+            Ok(Manifest::default())
+        }
+    }
+
+    pub fn save(&self, cache_dir: &Path) -> Result<()> {
+        // written next to the manifest then renamed, so an interruption never leaves it truncated
+        let path = cache_dir.join(FILE_NAME);
+        let tmp = cache_dir.join(format!("{}.tmp", FILE_NAME));
+        fs::create_dir_all(cache_dir)?;
+        fs::write(&tmp, serde_json::to_string(self)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Returns the size and SHA-256 of a file.
+pub fn hash_file(path: &Path) -> io::Result<(u64, String)> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/// Whether a file starts like one of the image formats served by Rebrickable.
+pub fn is_image(path: &Path) -> io::Result<bool> {
+    let mut header = [0u8; 12];
+    let mut file = fs::File::open(path)?;
+    let len = file.read(&mut header)?;
+    let header = &header[..len];
+    Ok(header.starts_with(&[0xFF, 0xD8, 0xFF])
+        || header.starts_with(b"\x89PNG\r\n\x1a\n")
+        || header.starts_with(b"GIF8")
+        || (header.starts_with(b"RIFF") && header.get(8..12) == Some(b"WEBP")))
+}
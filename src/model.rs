@@ -1,10 +1,31 @@
 use std::collections::{HashMap};
-use phf::phf_map;
+use anyhow::{anyhow, Result};
 use serde::ser::{Serialize, Serializer, SerializeStruct};
-use crate::utils::convert_asset_url;
-use crate::csv::{InventoryPartRecord, InventoryMinifigRecord, MinifigRecord, PartRecord, SetRecord};
+use crate::assets::AssetUrls;
+use crate::overrides::Overrides;
+use crate::csv::{ColorRecord, ElementRecord, InventoryPartRecord, InventoryMinifigRecord, MinifigRecord, PartCategoryRecord, PartRecord, PartRelationshipRecord, PartRelationshipType, SetRecord, ThemeRecord};
 use crate::csv::Data as CSVData;
 
+#[derive(Debug, serde::Serialize)]
+pub struct Color {
+    pub id: u32,
+    pub name: String,
+    pub rgb: String,
+    pub is_transparent: bool,
+    pub parts_count: u32,
+    pub sets_count: u32,
+    pub year1: Option<u16>,
+    pub year2: Option<u16>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Element {
+    pub id: String,
+    pub part_number: String,
+    pub color_id: u32,
+    pub design_id: Option<String>,
+}
+
 #[derive(Debug, serde::Serialize)]
 pub struct Minifig {
     pub number: String,
@@ -21,6 +42,19 @@ pub struct Part {
     pub material: String,
 }
 
+#[derive(Debug, serde::Serialize)]
+pub struct PartCategory {
+    pub id: u32,
+    pub name: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct PartRelationship {
+    pub kind: PartRelationshipType,
+    pub child_number: String,
+    pub parent_number: String,
+}
+
 #[derive(Debug)]
 pub struct Set {
     pub number: String,
@@ -34,16 +68,30 @@ pub struct Set {
     pub is_pack: bool,
     pub is_unreleased: bool,
     pub is_accessories: bool,
-    pub versions: Vec<SetVersion>,  // will not be serialized, instead it will be the minifigs and
-                                    // parts of the last version
+    pub versions: Vec<SetVersion>,  // with Versions::Last, will not be serialized, instead it will be
+                                    // the minifigs and parts of the last version
+    pub serialized_versions: Versions,
 } 
 
+/// Which inventories of a set are written in the JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Versions {
+    /// Only the minifigs, parts and spare parts of the last version
+    Last,
+    /// Every version, with its version number
+    All,
+}
+
+// Sets without inventory have no version, and so no minifigs or parts
 impl Set {
-    fn minifigs(&self) -> &Vec<SetMinifig> {
-        &self.versions.last().unwrap().minifigs
+    fn minifigs(&self) -> &[SetMinifig] {
+        self.versions.last().map_or(&[], |v| &v.minifigs)
     }
-    fn parts(&self) -> &Vec<SetPart> {
-        &self.versions.last().unwrap().parts
+    fn parts(&self) -> &[SetPart] {
+        self.versions.last().map_or(&[], |v| &v.parts)
+    }
+    fn spare_parts(&self) -> &[SetPart] {
+        self.versions.last().map_or(&[], |v| &v.spare_parts)
     }
 }
 
@@ -61,8 +109,14 @@ impl Serialize for Set {
         state.serialize_field("theme_id", &self.theme_id)?;
         state.serialize_field("parts_count", &self.parts_count)?;
         state.serialize_field("img_url", &self.img_url)?;
-        state.serialize_field("minifigs", &self.minifigs())?;
-        state.serialize_field("parts", &self.parts())?;
+        match self.serialized_versions {
+            Versions::Last => {
+                state.serialize_field("minifigs", &self.minifigs())?;
+                state.serialize_field("parts", &self.parts())?;
+                state.serialize_field("spare_parts", &self.spare_parts())?;
+            }
+            Versions::All => state.serialize_field("versions", &self.versions)?,
+        }
         state.serialize_field("is_pack", &self.is_pack)?;
         state.serialize_field("is_unreleased", &self.is_unreleased)?;
         state.serialize_field("is_accessories", &self.is_accessories)?;
@@ -70,11 +124,19 @@ impl Serialize for Set {
     }
 }
 
+#[derive(Debug, serde::Serialize)]
+pub struct Theme {
+    pub id: u32,
+    pub name: String,
+    pub parent_id: Option<u32>,
+}
+
 #[derive(Debug, serde::Serialize)]
 pub struct SetVersion {
     pub version: u16,
     pub minifigs: Vec<SetMinifig>,
     pub parts: Vec<SetPart>,
+    pub spare_parts: Vec<SetPart>,
 } 
 
 #[derive(Debug, serde::Serialize)]
@@ -93,37 +155,50 @@ pub struct SetPart {
 
 #[derive(Debug, serde::Serialize)]
 pub struct Data {
+    pub colors: Vec<Color>,
+    pub elements: Vec<Element>,
     pub minifigs: Vec<Minifig>,
     pub parts: Vec<Part>,
+    pub part_categories: Vec<PartCategory>,
+    pub part_relationships: Vec<PartRelationship>,
     pub sets: Vec<Set>,
+    pub themes: Vec<Theme>,
 }
 
-fn get_set_version(inventory_id: u32, version: u16, minifig_inventories: &HashMap<u32, Vec<InventoryMinifigRecord>>, part_inventories: &HashMap<u32, Vec<InventoryPartRecord>>, all_parts_keys: &HashMap<String, bool>) -> SetVersion {
+fn get_set_version(inventory_id: u32, version: u16, minifig_inventories: &HashMap<u32, Vec<InventoryMinifigRecord>>, part_inventories: &HashMap<u32, Vec<InventoryPartRecord>>, all_minifigs_keys: &HashMap<String, bool>, all_parts_keys: &HashMap<String, bool>, assets: &AssetUrls) -> Result<SetVersion> {
     let mut version = SetVersion {
         version,
         minifigs: vec![],
         parts: vec![],
+        spare_parts: vec![],
     };
 
     if let Some(minifigs) = minifig_inventories.get(&inventory_id) {
         for minifig in minifigs {
-            let minifig = SetMinifig {
-                number: minifig.fig_num.clone(), // TODO no clone
-                quantity: minifig.quantity,
-            };
-            version.minifigs.push(minifig);
-        } 
+            if all_minifigs_keys.contains_key(&minifig.fig_num) {
+                let minifig = SetMinifig {
+                    number: minifig.fig_num.clone(), // TODO no clone
+                    quantity: minifig.quantity,
+                };
+                version.minifigs.push(minifig);
+            } else {
+                eprintln!("Set Version {}: Ignoring minifig {}: does not exist", version.version, minifig.fig_num);
+            }
+        }
     }
     if let Some(parts) = part_inventories.get(&inventory_id) {
         for part in parts {
             if all_parts_keys.contains_key(&part.part_num) {
-                if part.is_spare != "True" {
-                    let part = SetPart {
-                        number: part.part_num.clone(), // TODO no clone
-                        quantity: part.quantity,
-                        color_id: part.color_id.try_into().unwrap(),
-                        img_url: convert_asset_url(&part.img_url),
-                    };
+                let is_spare = part.is_spare == "True";
+                let part = SetPart {
+                    number: part.part_num.clone(), // TODO no clone
+                    quantity: part.quantity,
+                    color_id: color_id(part.color_id)?,
+                    img_url: assets.rewrite(&part.img_url),
+                };
+                if is_spare {
+                    version.spare_parts.push(part);
+                } else {
                     version.parts.push(part);
                 }
             } else {
@@ -131,19 +206,40 @@ fn get_set_version(inventory_id: u32, version: u16, minifig_inventories: &HashMa
             }
         }
     }
-    version
+    Ok(version)
 }
 
-pub fn convert(csv_data: CSVData) -> Box<Data> {
+pub fn convert(csv_data: CSVData, overrides: &Overrides, assets: &AssetUrls, versions: Versions) -> Result<Box<Data>> {
     let mut parts: Vec<Part> = Vec::with_capacity(csv_data.parts.len());
     let mut parts_map: HashMap<String, bool> = HashMap::new();
     for part in csv_data.parts.into_iter() {
         parts_map.insert(part.part_num.clone(), true);
         parts.push(part_csv_to_model(part));
     }
+    let colors: Vec<Color> = csv_data.colors.into_iter().map(color_csv_to_model).collect::<Result<_>>()?;
+    let themes: Vec<Theme> = csv_data.themes.into_iter().map(theme_csv_to_model).collect();
+    let part_categories: Vec<PartCategory> = csv_data.part_categories.into_iter().map(part_category_csv_to_model).collect();
+    let mut elements: Vec<Element> = Vec::with_capacity(csv_data.elements.len());
+    for element in csv_data.elements.into_iter() {
+        if parts_map.contains_key(&element.part_num) {
+            elements.push(element_csv_to_model(element)?);
+        } else {
+            eprintln!("Element {}: Ignoring part {}: does not exist", element.element_id, element.part_num);
+        }
+    }
+    let mut part_relationships: Vec<PartRelationship> = Vec::with_capacity(csv_data.part_relationships.len());
+    for rel in csv_data.part_relationships.into_iter() {
+        if parts_map.contains_key(&rel.child_part_num) && parts_map.contains_key(&rel.parent_part_num) {
+            part_relationships.push(part_relationship_csv_to_model(rel));
+        } else {
+            eprintln!("Ignoring {:?} relationship {} -> {}: part does not exist", rel.rel_type, rel.child_part_num, rel.parent_part_num);
+        }
+    }
     let mut minifigs: Vec<Minifig> = Vec::with_capacity(csv_data.minifigs.len());
+    let mut minifigs_map: HashMap<String, bool> = HashMap::new();
     for minifig in csv_data.minifigs.into_iter() {
-        minifigs.push(minifig_csv_to_model(minifig));
+        minifigs_map.insert(minifig.fig_num.clone(), true);
+        minifigs.push(minifig_csv_to_model(minifig, assets));
     }
     let mut set_inventories: HashMap<String, Vec<(u32, u16)>> = HashMap::new(); 
     for inventory in csv_data.inventories.into_iter() {
@@ -162,21 +258,30 @@ pub fn convert(csv_data: CSVData) -> Box<Data> {
     }
     let mut sets: Vec<Set> = Vec::with_capacity(csv_data.sets.len());
     for set in csv_data.sets.into_iter() {
-        let key = set.set_num.clone();
-        let mut set = set_csv_to_model(set, is_pack(&key), is_unreleased(&key), is_accessories(&key));
+        let mut set = set_csv_to_model(set, overrides, assets, versions);
         if let Some(versions) = set_inventories.get(&set.number) {
             for version in versions {
-                let version = get_set_version(version.0, version.1, &minifig_inventories, &part_inventories, &parts_map);
+                let version = get_set_version(version.0, version.1, &minifig_inventories, &part_inventories, &minifigs_map, &parts_map, assets)?;
                 set.versions.push(version);
             }
         }
         sets.push(set);
     }
-    Box::new(Data{
+    Ok(Box::new(Data{
+        colors,
+        elements,
         minifigs,
         parts,
+        part_categories,
+        part_relationships,
         sets,
-    })
+        themes,
+    }))
+}
+
+// Color IDs are normalized to the non-negative IDs of ids.json when read, Rebrickable's -1 included
+fn color_id(id: i32) -> Result<u32> {
+    u32::try_from(id).map_err(|_| anyhow!("color {} is not normalized", id))
 }
 
 fn part_csv_to_model(part: PartRecord) -> Part {
@@ -188,24 +293,70 @@ fn part_csv_to_model(part: PartRecord) -> Part {
     }
 }
 
-fn minifig_csv_to_model(minifig: MinifigRecord) -> Minifig {
+fn color_csv_to_model(color: ColorRecord) -> Result<Color> {
+    Ok(Color {
+        id: color_id(color.id)?,
+        name: color.name,
+        rgb: color.rgb.to_string(),
+        is_transparent: color.is_trans,
+        parts_count: color.num_parts,
+        sets_count: color.num_sets,
+        year1: color.y1,
+        year2: color.y2,
+    })
+}
+
+fn theme_csv_to_model(theme: ThemeRecord) -> Theme {
+    Theme {
+        id: theme.id,
+        name: theme.name,
+        parent_id: theme.parent_id,
+    }
+}
+
+fn part_category_csv_to_model(part_category: PartCategoryRecord) -> PartCategory {
+    PartCategory {
+        id: part_category.id,
+        name: part_category.name,
+    }
+}
+
+fn element_csv_to_model(element: ElementRecord) -> Result<Element> {
+    Ok(Element {
+        id: element.element_id,
+        part_number: element.part_num,
+        color_id: color_id(element.color_id)?,
+        design_id: element.design_id,
+    })
+}
+
+fn part_relationship_csv_to_model(rel: PartRelationshipRecord) -> PartRelationship {
+    PartRelationship {
+        kind: rel.rel_type,
+        child_number: rel.child_part_num,
+        parent_number: rel.parent_part_num,
+    }
+}
+
+fn minifig_csv_to_model(minifig: MinifigRecord, assets: &AssetUrls) -> Minifig {
     Minifig {
         number: minifig.fig_num,
         name: minifig.name,
         parts_count: minifig.num_parts,
-        img_url: convert_asset_url(&minifig.img_url),
+        img_url: assets.rewrite(&minifig.img_url),
     }
 }
 
-fn set_csv_to_model(set: SetRecord, is_pack: bool, is_unreleased: bool, is_accessories: bool) -> Set {
-    let is_us = is_us_number(&set.set_num);
+fn set_csv_to_model(set: SetRecord, overrides: &Overrides, assets: &AssetUrls, versions: Versions) -> Set {
+    let is_us = overrides.is_us_number(&set.set_num);
     let same_as = if is_us {
-        get_intl_number(&set.set_num) 
-    } else if has_us_number(&set.set_num) {
-        Some(get_us_number(&set.set_num).unwrap())
+        overrides.get_intl_number(&set.set_num)
     } else {
-        None
+        overrides.get_us_number(&set.set_num)
     };
+    let is_pack = overrides.is_pack(&set.set_num);
+    let is_unreleased = overrides.is_unreleased(&set.set_num);
+    let is_accessories = overrides.is_accessories(&set.set_num);
     Set {
         number: set.set_num,
         is_us_number: is_us,
@@ -214,87 +365,11 @@ fn set_csv_to_model(set: SetRecord, is_pack: bool, is_unreleased: bool, is_acces
         year: set.year,
         parts_count: set.num_parts,
         theme_id: set.theme_id,
-        img_url: convert_asset_url(&set.img_url),
+        img_url: assets.rewrite(&set.img_url),
         versions: vec![],
+        serialized_versions: versions,
         is_pack,
         is_unreleased,
         is_accessories,
     }
 }
-
-static PACKS: phf::Map<&'static str, ()> = phf_map! {
-    "1507-1" => (),
-    "1510-1" => (),
-    "1616-1" => (),
-    "1969-2" => (),
-    "1977-1" => (),
-    "1983-1" => (),
-    "1999-1" => (),
-};
-
-pub fn is_pack(key: &str) -> bool {
-    PACKS.contains_key(key) 
-}
-
-static UNRELEASED: phf::Map<&'static str, ()> = phf_map! {
-    "1526-1" => (),
-};
-
-pub fn is_unreleased(key: &str) -> bool {
-    UNRELEASED.contains_key(key)
-}
-
-static ACCESSORIES: phf::Map<&'static str, ()> = phf_map! {
-   "6921-1" => (), 
-};
-
-pub fn is_accessories(key: &str) -> bool {
-    ACCESSORIES.contains_key(key)
-}
-
-static US_NUMBERS_TO_INTL: phf::Map<&'static str, Option<&'static str>> = phf_map! {
-    // classic space
-    "442-1" => Some("891-1"),
-    "452-1" => Some("894-1"),
-    "462-1" => Some("897-1"),
-    "483-1" => Some("920-2"),
-    "487-1" => Some("924-1"),
-    "493-1" => None,            // space base with the plate with the crater made of bricks
-    "493-3" => Some("926-1"),
-    "497-1" => Some("928-1"),
-    // classic castle
-    "6075-2" => Some("375-2"),
-};
-
-fn is_us_number(number: &str) -> bool {
-    US_NUMBERS_TO_INTL.contains_key(number)
-}
-
-fn get_intl_number(number: &str) -> Option<String> {
-    if let Some(n) = US_NUMBERS_TO_INTL.get(number) {
-        n.as_ref().map(|n| n.to_string())
-    } else {
-        None
-    }
-}
-
-static INTL_NUMBERS_TO_US: phf::Map<&'static str, &'static str> = phf_map! {
-    // classic space
-    "891-1" => "442-1",
-    "894-1" => "452-1",
-    "897-1" => "462-1",
-    "920-2" => "483-1",
-    "924-1" => "487-1",
-    "926-1" => "493-3",
-    "928-1" => "497-1",    
-    // classic castle
-    "375-2" => "6075-2",
-};
-
-fn has_us_number(number: &str) -> bool {
-    INTL_NUMBERS_TO_US.contains_key(number)
-}
-
-fn get_us_number(number: &str) -> Option<String> {
-    INTL_NUMBERS_TO_US.get(number).map(|n| n.to_string())
-}
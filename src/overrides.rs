@@ -0,0 +1,113 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use anyhow::{anyhow, Result};
+use crate::csv::SetRecord;
+use crate::findings::{self, Finding, Kind, Severity};
+
+pub const FILE_NAME: &str = "overrides.toml";
+
+static DEFAULT: &str = include_str!("../overrides.toml");
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OverridesFile {
+    #[serde(default)]
+    packs: Vec<String>,
+    #[serde(default)]
+    unreleased: Vec<String>,
+    #[serde(default)]
+    accessories: Vec<String>,
+    #[serde(default)]
+    us_only: Vec<String>,
+    #[serde(default)]
+    us_numbers: BTreeMap<String, String>,
+}
+
+/// Curated data that Rebrickable doesn't provide, loaded from `overrides.toml`.
+#[derive(Debug)]
+pub struct Overrides {
+    packs: HashSet<String>,
+    unreleased: HashSet<String>,
+    accessories: HashSet<String>,
+    us_numbers_to_intl: HashMap<String, Option<String>>,
+    intl_numbers_to_us: HashMap<String, String>,
+}
+
+impl Overrides {
+    fn parse(content: &str) -> Result<Self> {
+        let file: OverridesFile = toml::from_str(content)?;
+        let mut us_numbers_to_intl: HashMap<String, Option<String>> = HashMap::new();
+        let mut intl_numbers_to_us: HashMap<String, String> = HashMap::new();
+        for (us, intl) in file.us_numbers {
+            if let Some(other) = intl_numbers_to_us.insert(intl.clone(), us.clone()) {
+                return Err(anyhow!("{} is the international number of both {} and {}", intl, other, us));
+            }
+            us_numbers_to_intl.insert(us, Some(intl));
+        }
+        for us in file.us_only {
+            if us_numbers_to_intl.insert(us.clone(), None).is_some() {
+                return Err(anyhow!("{} is in us_only but has an international number", us));
+            }
+        }
+        if let Some(number) = intl_numbers_to_us.keys().find(|n| us_numbers_to_intl.contains_key(*n)) {
+            return Err(anyhow!("{} is both a US and an international number", number));
+        }
+        Ok(Overrides {
+            packs: file.packs.into_iter().collect(),
+            unreleased: file.unreleased.into_iter().collect(),
+            accessories: file.accessories.into_iter().collect(),
+            us_numbers_to_intl,
+            intl_numbers_to_us,
+        })
+    }
+
+    /// Returns a warning for every set number that isn't in `sets`.
+    pub fn check(&self, sets: &[SetRecord]) -> Vec<Finding> {
+        let known: HashSet<&str> = sets.iter().map(|s| s.set_num.as_str()).collect();
+        let mut numbers: Vec<(&str, &str)> = vec![];
+        numbers.extend(self.packs.iter().map(|n| ("packs", n.as_str())));
+        numbers.extend(self.unreleased.iter().map(|n| ("unreleased", n.as_str())));
+        numbers.extend(self.accessories.iter().map(|n| ("accessories", n.as_str())));
+        numbers.extend(self.us_numbers_to_intl.keys().map(|n| ("us_numbers", n.as_str())));
+        numbers.extend(self.intl_numbers_to_us.keys().map(|n| ("us_numbers", n.as_str())));
+        findings::group(Kind::UnknownSet, Severity::Warning, FILE_NAME, numbers.into_iter()
+            .filter(|(_, n)| !known.contains(n))
+            .map(|(section, n)| (n.to_string(), section.to_string())))
+    }
+
+    pub fn is_pack(&self, number: &str) -> bool {
+        self.packs.contains(number)
+    }
+
+    pub fn is_unreleased(&self, number: &str) -> bool {
+        self.unreleased.contains(number)
+    }
+
+    pub fn is_accessories(&self, number: &str) -> bool {
+        self.accessories.contains(number)
+    }
+
+    pub fn is_us_number(&self, number: &str) -> bool {
+        self.us_numbers_to_intl.contains_key(number)
+    }
+
+    pub fn get_intl_number(&self, number: &str) -> Option<String> {
+        self.us_numbers_to_intl.get(number).cloned().flatten()
+    }
+
+    pub fn get_us_number(&self, number: &str) -> Option<String> {
+        self.intl_numbers_to_us.get(number).cloned()
+    }
+}
+
+/// Loads `overrides.toml` from the working directory, or the built-in copy if there is none.
+pub fn load(workdir: &Path) -> Result<Overrides> {
+    let path = workdir.join(FILE_NAME);
+    if path.exists() {
+        let content = fs::read_to_string(&path)?;
+        Overrides::parse(&content).map_err(|err| anyhow!("{}: {}", path.display(), err))
+    } else {
+        Overrides::parse(DEFAULT)
+    }
+}
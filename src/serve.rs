@@ -0,0 +1,160 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::UNIX_EPOCH;
+use anyhow::{anyhow, Result};
+use tiny_http::{Header, Method, Request, Response, Server};
+use crate::assets::AssetUrls;
+use crate::cache;
+use crate::manifest::{self, Manifest};
+
+pub struct Options {
+    /// Address and port to listen on
+    pub address: String,
+    /// Number of requests served concurrently
+    pub jobs: usize,
+    /// File where the paths not found are appended
+    pub not_found_log: Option<PathBuf>,
+}
+
+// The cached file of a request path, with the manifest entry of its URL if any
+struct Found<'a> {
+    path: PathBuf,
+    entry: Option<&'a manifest::Entry>,
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase()).as_deref() {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name, value).unwrap()
+}
+
+fn with_headers<R: Read>(mut response: Response<R>, headers: [Header; 4]) -> Response<R> {
+    for header in headers {
+        response.add_header(header);
+    }
+    response
+}
+
+struct Cache<'a> {
+    cache_dir: &'a Path,
+    assets: &'a AssetUrls,
+    manifest: Manifest,
+    not_found_log: Option<Mutex<File>>,
+}
+
+impl Cache<'_> {
+    fn find(&self, path: &str) -> Option<Found<'_>> {
+        // never outside of the cache directory
+        let relative = path.strip_prefix('/')?;
+        if relative.split('/').any(|s| s.is_empty() || s == "." || s == ".." || s.contains('\\')) {
+            return None;
+        }
+        self.assets.source_urls(path).into_iter()
+            .filter_map(|url| {
+                let path = cache::cache_path(&url, self.cache_dir).ok()?;
+                path.is_file().then(|| Found { path, entry: self.manifest.entries.get(&url) })
+            })
+            .next()
+    }
+
+    fn not_found(&self, path: &str) -> Result<()> {
+        eprintln!("\x1b[31m404 {}\x1b[0m", path);
+        if let Some(log) = &self.not_found_log {
+            writeln!(log.lock().unwrap(), "{} {}", manifest::now(), path)?;
+        }
+        Ok(())
+    }
+
+    fn respond(&self, request: Request) -> Result<()> {
+        if ![Method::Get, Method::Head].contains(request.method()) {
+            request.respond(Response::empty(405).with_header(header("Allow", "GET, HEAD")))?;
+            return Ok(());
+        }
+        let path = request.url().split(['?', '#']).next().unwrap_or_default().to_string();
+        let Some(found) = self.find(&path) else {
+            self.not_found(&path)?;
+            request.respond(Response::from_string("Not Found").with_status_code(404))?;
+            return Ok(());
+        };
+        let metadata = fs::metadata(&found.path)?;
+        let modified = metadata.modified()?;
+        let last_modified = httpdate::fmt_http_date(modified);
+        // the checksum of the manifest is a strong validator, otherwise fall back to the size and time
+        let etag = match found.entry {
+            Some(entry) => format!("\"{}\"", entry.sha256),
+            None => {
+                let secs = modified.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+                format!("W/\"{}-{}\"", metadata.len(), secs)
+            }
+        };
+        let content_type = found.entry
+            .and_then(|e| e.content_type.as_deref())
+            .unwrap_or_else(|| content_type(&found.path));
+        let headers = [
+            header("Content-Type", content_type),
+            header("ETag", &etag),
+            header("Last-Modified", &last_modified),
+            header("Cache-Control", "public, max-age=86400"),
+        ];
+        let request_header = |name: &'static str| request.headers().iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.as_str().to_string());
+        let not_modified = match (request_header("If-None-Match"), request_header("If-Modified-Since")) {
+            (Some(tags), _) => tags.split(',').any(|t| t.trim() == etag || t.trim() == "*"),
+            (None, Some(since)) => match (httpdate::parse_http_date(&since), httpdate::parse_http_date(&last_modified)) {
+                (Ok(since), Ok(modified)) => modified <= since,
+                _ => false,
+            },
+            (None, None) => false,
+        };
+        // tiny_http leaves the body out of the responses to HEAD requests
+        if not_modified {
+            request.respond(with_headers(Response::empty(304), headers))?;
+        } else {
+            // with a Content-Length rather than chunked, so clients can show the progress
+            let response = Response::from_file(File::open(&found.path)?).with_chunked_threshold(usize::MAX);
+            request.respond(with_headers(response, headers))?;
+        }
+        Ok(())
+    }
+}
+
+/// Serves the files of the mirror cache at the paths of their rewritten asset URLs, until killed.
+pub fn serve(cache_dir: &Path, assets: &AssetUrls, options: &Options) -> Result<()> {
+    let not_found_log = match &options.not_found_log {
+        Some(path) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        None => None,
+    };
+    let cache = Cache {
+        cache_dir,
+        assets,
+        manifest: Manifest::load(cache_dir)?,
+        not_found_log,
+    };
+    let server = Server::http(&options.address).map_err(|err| anyhow!("{}: {}", options.address, err))?;
+    println!("Serving {} on http://{}/", cache_dir.display(), server.server_addr());
+    thread::scope(|scope| {
+        for _ in 0..options.jobs {
+            scope.spawn(|| {
+                for request in server.incoming_requests() {
+                    let description = format!("{} {}", request.method(), request.url());
+                    if let Err(err) = cache.respond(request) {
+                        eprintln!("\x1b[31m{} {}\x1b[0m", description, err);
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,182 @@
+use std::fs;
+use std::path::Path;
+use anyhow::Result;
+use rusqlite::{params, Connection};
+use crate::model::{Data, SetPart};
+
+const SCHEMA: &str = "
+CREATE TABLE colors (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    rgb TEXT NOT NULL,
+    is_transparent INTEGER NOT NULL,
+    parts_count INTEGER NOT NULL,
+    sets_count INTEGER NOT NULL,
+    year1 INTEGER,
+    year2 INTEGER
+);
+CREATE TABLE themes (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    parent_id INTEGER REFERENCES themes(id)
+);
+CREATE TABLE part_categories (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL
+);
+CREATE TABLE parts (
+    number TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    part_category_id INTEGER NOT NULL REFERENCES part_categories(id),
+    material TEXT NOT NULL
+);
+CREATE TABLE elements (
+    id TEXT PRIMARY KEY,
+    part_number TEXT NOT NULL REFERENCES parts(number),
+    color_id INTEGER NOT NULL REFERENCES colors(id),
+    design_id TEXT
+);
+CREATE TABLE part_relationships (
+    kind TEXT NOT NULL,
+    child_number TEXT NOT NULL REFERENCES parts(number),
+    parent_number TEXT NOT NULL REFERENCES parts(number)
+);
+CREATE TABLE minifigs (
+    number TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    parts_count INTEGER NOT NULL,
+    img_url TEXT
+);
+CREATE TABLE sets (
+    number TEXT PRIMARY KEY,
+    is_us_number INTEGER NOT NULL,
+    same_as_number TEXT,
+    name TEXT NOT NULL,
+    year INTEGER NOT NULL,
+    theme_id INTEGER NOT NULL REFERENCES themes(id),
+    parts_count INTEGER NOT NULL,
+    img_url TEXT,
+    is_pack INTEGER NOT NULL,
+    is_unreleased INTEGER NOT NULL,
+    is_accessories INTEGER NOT NULL
+);
+CREATE TABLE set_versions (
+    id INTEGER PRIMARY KEY,
+    set_number TEXT NOT NULL REFERENCES sets(number),
+    version INTEGER NOT NULL
+);
+CREATE TABLE set_minifigs (
+    set_version_id INTEGER NOT NULL REFERENCES set_versions(id),
+    minifig_number TEXT NOT NULL REFERENCES minifigs(number),
+    quantity INTEGER NOT NULL
+);
+CREATE TABLE set_parts (
+    set_version_id INTEGER NOT NULL REFERENCES set_versions(id),
+    part_number TEXT NOT NULL REFERENCES parts(number),
+    color_id INTEGER NOT NULL REFERENCES colors(id),
+    quantity INTEGER NOT NULL,
+    is_spare INTEGER NOT NULL,
+    img_url TEXT
+);
+";
+
+// Created after the inserts, which is faster than maintaining them row by row
+const INDICES: &str = "
+CREATE INDEX themes_parent_id ON themes(parent_id);
+CREATE INDEX parts_part_category_id ON parts(part_category_id);
+CREATE INDEX elements_part_number_color_id ON elements(part_number, color_id);
+CREATE INDEX part_relationships_child_number ON part_relationships(child_number);
+CREATE INDEX part_relationships_parent_number ON part_relationships(parent_number);
+CREATE INDEX sets_theme_id ON sets(theme_id);
+CREATE INDEX sets_year ON sets(year);
+CREATE UNIQUE INDEX set_versions_set_number_version ON set_versions(set_number, version);
+CREATE INDEX set_minifigs_set_version_id ON set_minifigs(set_version_id);
+CREATE INDEX set_minifigs_minifig_number ON set_minifigs(minifig_number);
+CREATE INDEX set_parts_set_version_id ON set_parts(set_version_id);
+CREATE INDEX set_parts_part_number_color_id ON set_parts(part_number, color_id);
+";
+
+/// Writes the BRIQ model in a new SQLite database at `path`, replacing any existing one once
+/// complete. Unlike init.json, every version of the sets is written.
+pub fn write(data: &Data, path: &Path) -> Result<()> {
+    // written next to the database then renamed, so a failure leaves the previous one as is
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    if tmp.exists() {
+        fs::remove_file(&tmp)?;
+    }
+    let result = write_database(data, &tmp);
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn write_database(data: &Data, path: &Path) -> Result<()> {
+    let mut conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+    let tx = conn.transaction()?;
+    // checked on commit, as themes can come before their parent
+    tx.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+    {
+        let mut stmt = tx.prepare("INSERT INTO colors VALUES (?, ?, ?, ?, ?, ?, ?, ?)")?;
+        for c in &data.colors {
+            stmt.execute(params![c.id, c.name, c.rgb, c.is_transparent, c.parts_count, c.sets_count, c.year1, c.year2])?;
+        }
+        let mut stmt = tx.prepare("INSERT INTO themes VALUES (?, ?, ?)")?;
+        for t in &data.themes {
+            stmt.execute(params![t.id, t.name, t.parent_id])?;
+        }
+        let mut stmt = tx.prepare("INSERT INTO part_categories VALUES (?, ?)")?;
+        for c in &data.part_categories {
+            stmt.execute(params![c.id, c.name])?;
+        }
+        let mut stmt = tx.prepare("INSERT INTO parts VALUES (?, ?, ?, ?)")?;
+        for p in &data.parts {
+            stmt.execute(params![p.number, p.name, p.part_category_id, p.material])?;
+        }
+        let mut stmt = tx.prepare("INSERT INTO elements VALUES (?, ?, ?, ?)")?;
+        for e in &data.elements {
+            stmt.execute(params![e.id, e.part_number, e.color_id, e.design_id])?;
+        }
+        let mut stmt = tx.prepare("INSERT INTO part_relationships VALUES (?, ?, ?)")?;
+        for r in &data.part_relationships {
+            let kind = serde_json::to_value(r.kind)?;
+            stmt.execute(params![kind.as_str(), r.child_number, r.parent_number])?;
+        }
+        let mut stmt = tx.prepare("INSERT INTO minifigs VALUES (?, ?, ?, ?)")?;
+        for m in &data.minifigs {
+            stmt.execute(params![m.number, m.name, m.parts_count, m.img_url])?;
+        }
+        let mut set_stmt = tx.prepare("INSERT INTO sets VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")?;
+        let mut version_stmt = tx.prepare("INSERT INTO set_versions (set_number, version) VALUES (?, ?)")?;
+        let mut minifig_stmt = tx.prepare("INSERT INTO set_minifigs VALUES (?, ?, ?)")?;
+        let mut part_stmt = tx.prepare("INSERT INTO set_parts VALUES (?, ?, ?, ?, ?, ?)")?;
+        let mut insert_part = |version_id: i64, part: &SetPart, is_spare: bool| {
+            part_stmt.execute(params![version_id, part.number, part.color_id, part.quantity, is_spare, part.img_url])
+        };
+        for s in &data.sets {
+            set_stmt.execute(params![s.number, s.is_us_number, s.same_as_number, s.name, s.year, s.theme_id,
+                s.parts_count, s.img_url, s.is_pack, s.is_unreleased, s.is_accessories])?;
+            for version in &s.versions {
+                version_stmt.execute(params![s.number, version.version])?;
+                let version_id = tx.last_insert_rowid();
+                for m in &version.minifigs {
+                    minifig_stmt.execute(params![version_id, m.number, m.quantity])?;
+                }
+                for p in &version.parts {
+                    insert_part(version_id, p, false)?;
+                }
+                for p in &version.spare_parts {
+                    insert_part(version_id, p, true)?;
+                }
+            }
+        }
+    }
+    tx.execute_batch(INDICES)?;
+    tx.commit()?;
+    Ok(())
+}
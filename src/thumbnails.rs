@@ -0,0 +1,94 @@
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use anyhow::{anyhow, Result};
+use image::{DynamicImage, ImageFormat, ImageReader};
+use image::imageops::FilterType;
+use crate::cache::{self, Mirror};
+use crate::assets::AssetUrls;
+use crate::utils::pluralize;
+
+pub struct Options {
+    /// Maximum width and height of the thumbnails, one directory per size
+    pub sizes: Vec<u32>,
+    /// Also write a WebP copy of every thumbnail
+    pub webp: bool,
+    /// Quality of the lossy WebP copies, from 0 to 100
+    pub webp_quality: f32,
+}
+
+// JPEG has no alpha channel, and WebP only supports 8-bit RGB(A)
+fn encodable(image: DynamicImage, format: ImageFormat) -> DynamicImage {
+    if format == ImageFormat::Jpeg || !image.color().has_alpha() {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    } else {
+        DynamicImage::ImageRgba8(image.to_rgba8())
+    }
+}
+
+/// Writes the thumbnails of the cached file of `url` in `output_dir`, skipping those newer than
+/// the file. Returns the number of thumbnails written.
+fn generate(url: &str, cache_dir: &Path, output_dir: &Path, assets: &AssetUrls, options: &Options) -> Result<usize> {
+    let source = cache::cache_path(url, cache_dir)?;
+    if !source.exists() {
+        return Err(anyhow!("not cached"));
+    }
+    let relative = assets.relative_path(url).ok_or_else(|| anyhow!("matches no rule of {}", crate::assets::FILE_NAME))?;
+    let mut targets = vec![];
+    for size in &options.sizes {
+        let path = output_dir.join(size.to_string()).join(relative);
+        if options.webp {
+            targets.push((*size, path.with_extension("webp"), ImageFormat::WebP));
+        }
+        let format = ImageFormat::from_path(&path).map_err(|_| anyhow!("unknown image format"))?;
+        targets.push((*size, path, format));
+    }
+    let modified = fs::metadata(&source)?.modified()?;
+    targets.retain(|(_, path, _)| !fs::metadata(path).and_then(|m| m.modified()).is_ok_and(|t| t >= modified));
+    if targets.is_empty() {
+        return Ok(0);
+    }
+    let image = ImageReader::open(&source)?.with_guessed_format()?.decode()?;
+    for (size, path, format) in &targets {
+        // never upscaled, the aspect ratio is preserved
+        let thumbnail = if image.width() > *size || image.height() > *size {
+            image.resize(*size, *size, FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial = cache::partial_path(path);
+        let thumbnail = encodable(thumbnail, *format);
+        if *format == ImageFormat::WebP {
+            // the encoder of image is lossless only, which makes bigger files than the JPEGs
+            let encoder = webp::Encoder::from_image(&thumbnail).map_err(|err| anyhow!("WebP: {}", err))?;
+            let encoded = encoder.encode_simple(false, options.webp_quality)
+                .map_err(|err| anyhow!("WebP: {:?}", err))?;
+            fs::write(&partial, &*encoded)?;
+        } else {
+            thumbnail.save_with_format(&partial, *format)?;
+        }
+        fs::rename(&partial, path)?;
+    }
+    Ok(targets.len())
+}
+
+/// Generates the thumbnails of the cached files of `urls` in `output_dir`, with the workers of
+/// `mirror`. Their paths are those of the rewritten asset URLs, under a directory per size.
+/// Returns the number of failures.
+pub fn generate_all(mirror: &Mirror, urls: &[String], cache_dir: &Path, output_dir: &Path, assets: &AssetUrls, options: &Options) -> usize {
+    let failed = AtomicUsize::new(0);
+    mirror.for_each(urls, |url, progress| {
+        match generate(url, cache_dir, output_dir, assets, options) {
+            Ok(0) => println!("{:.2}% {} up to date.", progress, url),
+            Ok(count) => println!("{:.2}% {} {} written.", progress, url, pluralize(count, "thumbnail")),
+            Err(err) => {
+                failed.fetch_add(1, Ordering::Relaxed);
+                eprintln!("\x1b[31m{:.2}% {} {}\x1b[0m", progress, url, err);
+            }
+        }
+    });
+    failed.into_inner()
+}
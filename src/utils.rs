@@ -8,11 +8,16 @@ pub fn pluralize(count: usize, word: &str) -> String {
     }
 }
 
-pub fn convert_asset_url(url: &str) -> Option<String> {
-    if !url.is_empty() {
-        static PREFIX: &str = "https://cdn.rebrickable.com/media";
-        return Some(format!("https://briq-assets.spe.quebec{}", &url[PREFIX.len()..]));
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
     }
-    None
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
 }
-